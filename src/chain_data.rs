@@ -35,6 +35,7 @@ pub struct AccountData {
 ///
 /// - use account() to retrieve the current best data for an account.
 /// - update_from_snapshot() and update_from_websocket() update the state for new messages
+#[derive(Clone)]
 pub struct ChainData {
     /// only slots >= newest_rooted_slot are retained
     slots: HashMap<u64, SlotData>,
@@ -261,6 +262,22 @@ impl ChainData {
             .unwrap_or(write.slot <= self.newest_rooted_slot)
     }
 
+    /// Consider all account writes up to `slot` final
+    ///
+    /// For use without websocket slot updates, like when only working with snapshots.
+    pub fn mark_rooted(&mut self, slot: u64) {
+        self.update_slot(SlotData {
+            slot,
+            parent: None,
+            status: SlotStatus::Rooted,
+            chain: 0,
+        });
+    }
+
+    pub fn newest_processed_slot(&self) -> u64 {
+        self.newest_processed_slot
+    }
+
     /// Cloned snapshot of all the most recent live writes per pubkey
     pub fn accounts_snapshot(&self) -> HashMap<Pubkey, AccountData> {
         self.accounts
@@ -275,8 +292,8 @@ impl ChainData {
             .collect()
     }
 
-    /// Ref to the most recent live write of the pubkey
-    pub fn account<'a>(&'a self, pubkey: &Pubkey) -> anyhow::Result<&'a AccountSharedData> {
+    /// Ref to the most recent live write of the pubkey, including its slot
+    pub fn account_data<'a>(&'a self, pubkey: &Pubkey) -> anyhow::Result<&'a AccountData> {
         self.accounts
             .get(pubkey)
             .ok_or_else(|| anyhow::anyhow!("account {} not found", pubkey))?
@@ -284,6 +301,10 @@ impl ChainData {
             .rev()
             .find(|w| self.is_account_write_live(w))
             .ok_or_else(|| anyhow::anyhow!("account {} has no live data", pubkey))
-            .map(|w| &w.account)
+    }
+
+    /// Ref to the most recent live write of the pubkey
+    pub fn account<'a>(&'a self, pubkey: &Pubkey) -> anyhow::Result<&'a AccountSharedData> {
+        self.account_data(pubkey).map(|w| &w.account)
     }
 }
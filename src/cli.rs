@@ -0,0 +1,215 @@
+use {
+    crate::Config,
+    anyhow::Context,
+    clap::{Args, Parser},
+    std::fs::File,
+    std::io::Read,
+};
+
+/// Every Config field can be set through a flag or environment variable,
+/// taking precedence over the config file.
+#[derive(Args, Debug)]
+pub struct ConfigOverrides {
+    #[clap(long, env)]
+    rpc_ws_url: Option<String>,
+    #[clap(long, env)]
+    rpc_http_url: Option<String>,
+    /// "v3" is the only version
+    #[clap(long, env)]
+    mango_program_version: Option<String>,
+    #[clap(long, env)]
+    mango_program_id: Option<String>,
+    #[clap(long, env)]
+    mango_group_id: Option<String>,
+    #[clap(long, env)]
+    mango_cache_id: Option<String>,
+    #[clap(long, env)]
+    mango_signer_id: Option<String>,
+    #[clap(long, env)]
+    serum_program_id: Option<String>,
+    #[clap(long, env)]
+    snapshot_interval_secs: Option<u64>,
+    #[clap(long, env)]
+    websocket_server_bind_address: Option<String>,
+    #[clap(long, env)]
+    parallel_rpc_requests: Option<usize>,
+    #[clap(long, env)]
+    get_multiple_accounts_count: Option<usize>,
+    #[clap(long, env)]
+    early_candidate_percentage: Option<f64>,
+    #[clap(long, env)]
+    oracle_max_staleness_secs: Option<u64>,
+    /// Comma separated list of ORACLE=SECS pairs
+    #[clap(long, env, value_delimiter = ',', parse(try_from_str = parse_oracle_staleness))]
+    oracle_max_staleness_overrides: Vec<(String, u64)>,
+    /// "annotate" or "suppress"
+    #[clap(long, env)]
+    stale_oracle_handling: Option<String>,
+    #[clap(long, env)]
+    full_health_check_interval_secs: Option<u64>,
+    #[clap(long, env)]
+    log_level: Option<String>,
+    #[clap(long, env)]
+    shutdown_grace_period_secs: Option<u64>,
+    #[clap(long, env)]
+    postgres_connection_string: Option<String>,
+    #[clap(long, env)]
+    sqlite_path: Option<String>,
+    #[clap(long, env)]
+    record_path: Option<String>,
+    #[clap(long, env)]
+    health_check_threads: Option<usize>,
+    #[clap(long, env)]
+    websocket_client_max_lag: Option<usize>,
+    #[clap(long, env)]
+    status_server_bind_address: Option<String>,
+    #[clap(long, env)]
+    status_max_slot_age_secs: Option<u64>,
+    #[clap(long, env)]
+    status_max_snapshot_age_secs: Option<u64>,
+    #[clap(long, env)]
+    candidate_hysteresis_percentage: Option<f64>,
+    #[clap(long, env)]
+    candidate_min_change_interval_secs: Option<u64>,
+    #[clap(long, env)]
+    websocket_server_tls_cert_path: Option<String>,
+    #[clap(long, env)]
+    websocket_server_tls_key_path: Option<String>,
+}
+
+fn parse_oracle_staleness(value: &str) -> anyhow::Result<(String, u64)> {
+    let (oracle, secs) = value.split_once('=').context("expected ORACLE=SECS")?;
+    Ok((oracle.to_string(), secs.parse().context("parsing SECS")?))
+}
+
+impl ConfigOverrides {
+    fn apply(&self, table: &mut toml::value::Table) -> anyhow::Result<()> {
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = &self.$field {
+                        table.insert(stringify!($field).into(), toml::Value::try_from(value)?);
+                    }
+                )*
+            };
+        }
+        apply!(
+            rpc_ws_url,
+            rpc_http_url,
+            mango_program_version,
+            mango_program_id,
+            mango_group_id,
+            mango_cache_id,
+            mango_signer_id,
+            serum_program_id,
+            snapshot_interval_secs,
+            websocket_server_bind_address,
+            parallel_rpc_requests,
+            get_multiple_accounts_count,
+            early_candidate_percentage,
+            oracle_max_staleness_secs,
+            stale_oracle_handling,
+            full_health_check_interval_secs,
+            log_level,
+            shutdown_grace_period_secs,
+            postgres_connection_string,
+            sqlite_path,
+            record_path,
+            health_check_threads,
+            websocket_client_max_lag,
+            status_server_bind_address,
+            status_max_slot_age_secs,
+            status_max_snapshot_age_secs,
+            candidate_hysteresis_percentage,
+            candidate_min_change_interval_secs,
+            websocket_server_tls_cert_path,
+            websocket_server_tls_key_path,
+        );
+
+        if !self.oracle_max_staleness_overrides.is_empty() {
+            let overrides = self
+                .oracle_max_staleness_overrides
+                .iter()
+                .map(|(oracle, secs)| Ok((oracle.clone(), toml::Value::try_from(secs)?)))
+                .collect::<anyhow::Result<toml::value::Table>>()?;
+            table.insert(
+                "oracle_max_staleness_overrides".into(),
+                toml::Value::Table(overrides),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Notifies websocket clients about liquidatable Mango accounts
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct Cli {
+    /// Path to the TOML config file, see example-config.toml
+    #[clap(long, env = "CONFIG_FILE", conflicts_with = "config-file")]
+    config: Option<String>,
+
+    /// Same as --config
+    #[clap(name = "config-file")]
+    config_file: Option<String>,
+
+    /// Replay a file written with record_path instead of connecting to the RPC node
+    #[clap(long)]
+    pub replay: Option<String>,
+
+    /// Replay speed relative to the recording, 0 replays as fast as possible
+    #[clap(long, default_value = "1.0")]
+    pub replay_speed: f64,
+
+    /// Check all accounts in a single snapshot, print the candidates as JSON and exit
+    #[clap(long)]
+    pub once: bool,
+
+    /// With --once, use the last snapshot in this recording instead of requesting one
+    #[clap(long, requires = "once")]
+    pub snapshot_file: Option<String>,
+
+    #[clap(flatten)]
+    overrides: ConfigOverrides,
+}
+
+impl Cli {
+    pub fn config_path(&self) -> Option<&str> {
+        self.config.as_deref().or(self.config_file.as_deref())
+    }
+
+    /// Read the config file, if any, and apply overrides from flags and environment
+    pub fn load_config(&self) -> anyhow::Result<Config> {
+        let mut table = match self.config_path() {
+            Some(path) => {
+                let mut file =
+                    File::open(path).with_context(|| format!("opening config file {}", path))?;
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                toml::from_str(&contents).context("parsing config file")?
+            }
+            None => toml::value::Table::new(),
+        };
+        self.overrides.apply(&mut table)?;
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .context("invalid configuration")?;
+        // intervals can't be zero, tokio::time::interval would panic
+        if config.full_health_check_interval_secs == 0 {
+            anyhow::bail!("full_health_check_interval_secs must be greater than 0");
+        }
+        if config.snapshot_interval_secs == 0 {
+            anyhow::bail!("snapshot_interval_secs must be greater than 0");
+        }
+
+        if self.replay.is_some() || self.snapshot_file.is_some() {
+            // the recorded prices are compared to the current time, they'd all be stale
+            config.oracle_max_staleness_secs = 0;
+            config.oracle_max_staleness_overrides.clear();
+            // don't record the replay
+            config.record_path = None;
+        }
+
+        Ok(config)
+    }
+}
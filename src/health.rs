@@ -0,0 +1,855 @@
+// Health and liquidation computations for mango v3 accounts, usable by other
+// tools that track the needed accounts in a ChainData.
+
+use {
+    crate::{chain_data::ChainData, now_unix_timestamp},
+    anyhow::Context,
+    fixed::types::I80F48,
+    mango::state::{
+        DataType, HealthCache, HealthType, MangoAccount, MangoCache, MangoGroup, UserActiveAssets,
+        MAX_PAIRS, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48,
+    },
+    mango_common::Loadable,
+    solana_sdk::account::{AccountSharedData, ReadableAccount},
+    solana_sdk::pubkey::Pubkey,
+    std::collections::HashMap,
+};
+
+/// The largest liquidation that can currently be done on an account
+#[derive(Clone, Debug)]
+pub enum LiquidationSuggestion {
+    TokenAndToken {
+        asset_index: usize,
+        asset_mint: Pubkey,
+        liab_index: usize,
+        liab_mint: Pubkey,
+        max_liab_transfer: I80F48, // native liab tokens
+        asset_transfer: I80F48,    // native asset tokens received for max_liab_transfer
+    },
+    PerpMarket {
+        market_index: usize,
+        perp_market: Pubkey,
+        base_position: i64,     // lots, negative for shorts
+        max_base_transfer: i64, // lots
+    },
+}
+
+/// Deposits and borrows of one token, in native units
+#[derive(Clone, Debug)]
+pub struct TokenBalance {
+    pub token_index: usize,
+    pub mint: Pubkey,
+    pub deposit: I80F48,
+    pub borrow: I80F48,
+}
+
+#[derive(Debug)]
+pub struct Health {
+    pub being_liquidated: bool,
+    pub health_fraction: I80F48, // always maint
+    pub assets: I80F48,          // always maint
+    pub liabilities: I80F48,     // always maint
+    pub init_health: I80F48,
+    /// the account has liabilities that can't be covered by liquidation anymore,
+    /// it needs bankruptcy resolution
+    pub bankrupt: bool,
+    /// some price data that the health depends on is older than allowed
+    pub stale_oracle: bool,
+    /// lowest and highest write slot of the account data the health was computed from
+    pub min_slot: u64,
+    pub max_slot: u64,
+}
+
+// FUTURE: It'd be very nice if I could map T to the DataType::T constant!
+pub fn load_mango_account<T: Loadable + Sized>(
+    data_type: DataType,
+    account: &AccountSharedData,
+) -> anyhow::Result<&T> {
+    let data = account.data();
+    let data_type_int = data_type as u8;
+    if data.len() != std::mem::size_of::<T>() {
+        anyhow::bail!(
+            "bad account size for {}: {} expected {}",
+            data_type_int,
+            data.len(),
+            std::mem::size_of::<T>()
+        );
+    }
+    if data[0] != data_type_int {
+        anyhow::bail!(
+            "unexpected data type for {}, got {}",
+            data_type_int,
+            data[0]
+        );
+    }
+    return Ok(Loadable::load_from_bytes(&data).expect("always Ok"));
+}
+
+pub fn load_mango_account_from_chain<'a, T: Loadable + Sized>(
+    data_type: DataType,
+    chain_data: &'a ChainData,
+    pubkey: &Pubkey,
+) -> anyhow::Result<&'a T> {
+    load_mango_account::<T>(
+        data_type,
+        chain_data
+            .account(pubkey)
+            .context("retrieving account from chain")?,
+    )
+}
+
+pub fn load_open_orders_account(
+    account: &AccountSharedData,
+) -> anyhow::Result<&serum_dex::state::OpenOrders> {
+    let data = account.data();
+    let expected_size = 12 + std::mem::size_of::<serum_dex::state::OpenOrders>();
+    if data.len() != expected_size {
+        anyhow::bail!(
+            "bad open orders account size: {} expected {}",
+            data.len(),
+            expected_size
+        );
+    }
+    if &data[0..5] != "serum".as_bytes() {
+        anyhow::bail!("unexpected open orders account prefix");
+    }
+    Ok(bytemuck::from_bytes::<serum_dex::state::OpenOrders>(
+        &data[5..data.len() - 7],
+    ))
+}
+
+pub fn get_open_orders<'a>(
+    chain_data: &'a ChainData,
+    group: &MangoGroup,
+    account: &'a MangoAccount,
+) -> anyhow::Result<Vec<Option<&'a serum_dex::state::OpenOrders>>> {
+    let mut unpacked = vec![None; MAX_PAIRS];
+    for i in 0..group.num_oracles {
+        if account.in_margin_basket[i] {
+            let oo = chain_data.account(&account.spot_open_orders[i])?;
+            unpacked[i] = Some(load_open_orders_account(oo)?);
+        }
+    }
+    Ok(unpacked)
+}
+
+/// Value of the token deposits and borrows of an account, in native quote
+fn token_values(
+    group: &MangoGroup,
+    cache: &MangoCache,
+    account: &MangoAccount,
+) -> anyhow::Result<Vec<(usize, I80F48, I80F48)>> {
+    let mut values = vec![];
+    for i in (0..group.num_oracles).chain(std::iter::once(QUOTE_INDEX)) {
+        if group.tokens[i].mint == Pubkey::default() {
+            continue;
+        }
+        let bank_cache = &cache.root_bank_cache[i];
+        let price = cache.get_price(i);
+        let deposit = account.get_native_deposit(bank_cache, i)? * price;
+        let borrow = account.get_native_borrow(bank_cache, i)? * price;
+        values.push((i, deposit, borrow));
+    }
+    Ok(values)
+}
+
+/// Mirrors the transfer limits of the mango program's liquidate_token_and_token:
+/// the liquidator stops once init health is back at zero, the liability is repaid
+/// or the asset is exhausted.
+fn token_and_token_suggestion(
+    group: &MangoGroup,
+    cache: &MangoCache,
+    account: &MangoAccount,
+    init_health: I80F48,
+    asset_index: usize,
+    liab_index: usize,
+) -> anyhow::Result<Option<LiquidationSuggestion>> {
+    let asset_price = cache.get_price(asset_index);
+    let liab_price = cache.get_price(liab_index);
+    if asset_price <= 0 || liab_price <= 0 {
+        return Ok(None);
+    }
+
+    let (asset_fee, init_asset_weight) = if asset_index == QUOTE_INDEX {
+        (ONE_I80F48, ONE_I80F48)
+    } else {
+        let info = &group.spot_markets[asset_index];
+        (ONE_I80F48 + info.liquidation_fee, info.init_asset_weight)
+    };
+    let (liab_fee, init_liab_weight) = if liab_index == QUOTE_INDEX {
+        (ONE_I80F48, ONE_I80F48)
+    } else {
+        let info = &group.spot_markets[liab_index];
+        (ONE_I80F48 - info.liquidation_fee, info.init_liab_weight)
+    };
+
+    let health_per_liab =
+        liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee);
+    if health_per_liab <= 0 {
+        return Ok(None);
+    }
+    let deficit_max_liab = -init_health / health_per_liab;
+
+    let native_deposit =
+        account.get_native_deposit(&cache.root_bank_cache[asset_index], asset_index)?;
+    let native_borrow =
+        account.get_native_borrow(&cache.root_bank_cache[liab_index], liab_index)?;
+    let asset_implied_liab = native_deposit * asset_price * liab_fee / (liab_price * asset_fee);
+
+    let max_liab_transfer = deficit_max_liab.min(native_borrow).min(asset_implied_liab);
+    if max_liab_transfer <= 0 {
+        return Ok(None);
+    }
+    let asset_transfer = max_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
+
+    Ok(Some(LiquidationSuggestion::TokenAndToken {
+        asset_index,
+        asset_mint: group.tokens[asset_index].mint,
+        liab_index,
+        liab_mint: group.tokens[liab_index].mint,
+        max_liab_transfer,
+        asset_transfer,
+    }))
+}
+
+/// Mirrors the transfer limits of the mango program's liquidate_perp_market:
+/// the liquidator takes over base lots until init health is back at zero.
+fn perp_market_suggestion(
+    group: &MangoGroup,
+    cache: &MangoCache,
+    account: &MangoAccount,
+    init_health: I80F48,
+    market_index: usize,
+) -> Option<LiquidationSuggestion> {
+    let info = &group.perp_markets[market_index];
+    let base_position = account.perp_accounts[market_index].base_position;
+    let lot_price = cache.get_price(market_index) * I80F48::from_num(info.base_lot_size);
+
+    let health_per_lot = if base_position > 0 {
+        lot_price * (ONE_I80F48 - info.init_asset_weight - info.liquidation_fee)
+    } else {
+        lot_price * (-ONE_I80F48 + info.init_liab_weight - info.liquidation_fee)
+    };
+    if health_per_lot <= 0 {
+        return None;
+    }
+    let deficit_max_lots = (-init_health / health_per_lot)
+        .checked_ceil()?
+        .checked_to_num::<i64>()?;
+    let max_base_transfer = deficit_max_lots.min(base_position.abs());
+    if max_base_transfer <= 0 {
+        return None;
+    }
+
+    Some(LiquidationSuggestion::PerpMarket {
+        market_index,
+        perp_market: info.perp_market,
+        base_position,
+        max_base_transfer,
+    })
+}
+
+/// Pick the liquidation that moves the most value: the token pair with the
+/// largest deposit against the largest borrow, or the largest perp base
+/// position, whichever is bigger.
+///
+/// The mango program only liquidates accounts whose maint health is negative
+/// or that are already being liquidated, that's `liquidatable`.
+fn best_liquidation(
+    group: &MangoGroup,
+    cache: &MangoCache,
+    account: &MangoAccount,
+    init_health: I80F48,
+    liquidatable: bool,
+) -> anyhow::Result<Option<LiquidationSuggestion>> {
+    if !liquidatable || init_health >= 0 {
+        return Ok(None);
+    }
+
+    // a token can't be liquidated against itself
+    let values = token_values(group, cache, account)?;
+    let best_pair = values
+        .iter()
+        .filter(|(_, deposit, _)| *deposit > 0)
+        .flat_map(|(asset_index, deposit, _)| {
+            values
+                .iter()
+                .filter(move |(liab_index, _, borrow)| *borrow > 0 && liab_index != asset_index)
+                .map(move |(liab_index, _, borrow)| {
+                    (*asset_index, *liab_index, (*deposit).min(*borrow))
+                })
+        })
+        .max_by_key(|(_, _, value)| *value);
+
+    let largest_perp = (0..group.num_oracles)
+        .filter(|&i| {
+            group.perp_markets[i].perp_market != Pubkey::default()
+                && account.perp_accounts[i].base_position != 0
+        })
+        .map(|i| {
+            let base_lots = I80F48::from_num(account.perp_accounts[i].base_position.abs());
+            let base_lot_size = I80F48::from_num(group.perp_markets[i].base_lot_size);
+            (i, base_lots * base_lot_size * cache.get_price(i))
+        })
+        .max_by_key(|(_, value)| *value);
+
+    let token_value = best_pair.map(|(_, _, value)| value).unwrap_or(ZERO_I80F48);
+    if let Some((perp_index, perp_value)) = largest_perp {
+        if perp_value > token_value {
+            return Ok(perp_market_suggestion(
+                group,
+                cache,
+                account,
+                init_health,
+                perp_index,
+            ));
+        }
+    }
+
+    match best_pair {
+        Some((asset_index, liab_index, _)) => {
+            token_and_token_suggestion(group, cache, account, init_health, asset_index, liab_index)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Value received minus value paid by a liquidator doing `suggestion`, in native quote
+///
+/// That's the liquidation fee on the transferred amount. Insurance fund payouts
+/// for bankrupt accounts are not included.
+fn estimated_profit(
+    group: &MangoGroup,
+    cache: &MangoCache,
+    suggestion: &LiquidationSuggestion,
+) -> I80F48 {
+    match suggestion {
+        LiquidationSuggestion::TokenAndToken {
+            asset_index,
+            liab_index,
+            max_liab_transfer,
+            asset_transfer,
+            ..
+        } => {
+            *asset_transfer * cache.get_price(*asset_index)
+                - *max_liab_transfer * cache.get_price(*liab_index)
+        }
+        LiquidationSuggestion::PerpMarket {
+            market_index,
+            max_base_transfer,
+            ..
+        } => {
+            let info = &group.perp_markets[*market_index];
+            I80F48::from_num(*max_base_transfer)
+                * I80F48::from_num(info.base_lot_size)
+                * cache.get_price(*market_index)
+                * info.liquidation_fee
+        }
+    }
+}
+
+/// Maximum age of cached prices, root banks and perp funding before they are considered stale
+#[derive(Clone, Debug, Default)]
+pub struct StalenessLimits {
+    /// limit in seconds, 0 means unlimited
+    pub default_max_age: u64,
+    /// per oracle limits in seconds, overriding the default
+    pub oracle_max_age: HashMap<Pubkey, u64>,
+}
+
+impl StalenessLimits {
+    pub fn max_age(&self, oracle: &Pubkey) -> u64 {
+        self.oracle_max_age
+            .get(oracle)
+            .copied()
+            .unwrap_or(self.default_max_age)
+    }
+
+    pub fn is_stale(&self, max_age: u64, last_update: u64, now: u64) -> bool {
+        max_age > 0 && now.saturating_sub(last_update) > max_age
+    }
+
+    /// Checks the cache entries that the health of an account with these active assets depends on
+    fn any_stale(
+        &self,
+        group: &MangoGroup,
+        cache: &MangoCache,
+        active_assets: &UserActiveAssets,
+        now: u64,
+    ) -> bool {
+        let quote_bank = &cache.root_bank_cache[QUOTE_INDEX];
+        if self.is_stale(self.default_max_age, quote_bank.last_update, now) {
+            return true;
+        }
+        (0..group.num_oracles).any(|i| {
+            if !active_assets.spot[i] && !active_assets.perps[i] {
+                return false;
+            }
+            let max_age = self.max_age(&group.oracles[i]);
+            self.is_stale(max_age, cache.price_cache[i].last_update, now)
+                || (active_assets.spot[i]
+                    && self.is_stale(max_age, cache.root_bank_cache[i].last_update, now))
+                || (active_assets.perps[i]
+                    && self.is_stale(max_age, cache.perp_market_cache[i].last_update, now))
+        })
+    }
+}
+
+fn check_health(
+    group: &MangoGroup,
+    cache: &MangoCache,
+    account: &MangoAccount,
+    open_orders: &Vec<Option<&serum_dex::state::OpenOrders>>,
+    staleness_limits: &StalenessLimits,
+    (min_slot, max_slot): (u64, u64),
+) -> anyhow::Result<Health> {
+    let assets = UserActiveAssets::new(group, account, vec![]);
+    let stale_oracle = staleness_limits.any_stale(group, cache, &assets, now_unix_timestamp());
+    let mut health_cache = HealthCache::new(assets);
+    health_cache.init_vals_with_orders_vec(group, cache, account, open_orders)?;
+
+    let (assets, liabilities) = health_cache.get_health_components(group, HealthType::Maint);
+    let health_fraction = if liabilities > 0 {
+        assets / liabilities
+    } else {
+        I80F48::MAX
+    };
+
+    let init_health = health_cache.get_health(group, HealthType::Init);
+    let still_being_liquidated = account.being_liquidated && init_health < 0;
+    let bankrupt = account.is_bankrupt || (still_being_liquidated && assets <= 0);
+
+    Ok(Health {
+        being_liquidated: still_being_liquidated,
+        health_fraction,
+        assets,
+        liabilities,
+        init_health,
+        bankrupt,
+        stale_oracle,
+        min_slot,
+        max_slot,
+    })
+}
+
+/// Lowest and highest write slot of the cache, the account and its OpenOrders accounts
+///
+/// The group is left out, it rarely changes.
+fn write_slot_range(
+    chain_data: &ChainData,
+    group: &MangoGroup,
+    cache_id: &Pubkey,
+    account_id: &Pubkey,
+    account: &MangoAccount,
+) -> anyhow::Result<(u64, u64)> {
+    let mut slots = vec![
+        chain_data.account_data(cache_id)?.slot,
+        chain_data.account_data(account_id)?.slot,
+    ];
+    for i in 0..group.num_oracles {
+        if account.in_margin_basket[i] {
+            slots.push(chain_data.account_data(&account.spot_open_orders[i])?.slot);
+        }
+    }
+    let min_slot = slots.iter().copied().min().unwrap_or(0);
+    let max_slot = slots.iter().copied().max().unwrap_or(0);
+    Ok((min_slot, max_slot))
+}
+
+/// Token and market indexes whose cache entries the health of `account` depends on
+///
+/// QUOTE_INDEX is included if the account has quote deposits or borrows.
+pub fn price_dependencies(group: &MangoGroup, account: &MangoAccount) -> Vec<usize> {
+    let active_assets = UserActiveAssets::new(group, account, vec![]);
+    let mut indexes = (0..group.num_oracles)
+        .filter(|&i| active_assets.spot[i] || active_assets.perps[i])
+        .collect::<Vec<usize>>();
+    if !account.deposits[QUOTE_INDEX].is_zero() || !account.borrows[QUOTE_INDEX].is_zero() {
+        indexes.push(QUOTE_INDEX);
+    }
+    indexes
+}
+
+/// Token and market indexes whose prices, bank indexes or perp funding differ between two caches
+///
+/// Only update times changing doesn't count.
+pub fn changed_cache_entries(group: &MangoGroup, old: &MangoCache, new: &MangoCache) -> Vec<usize> {
+    let bank_changed = |i: usize| {
+        let (old, new) = (&old.root_bank_cache[i], &new.root_bank_cache[i]);
+        old.deposit_index != new.deposit_index || old.borrow_index != new.borrow_index
+    };
+    let mut indexes = (0..group.num_oracles)
+        .filter(|&i| {
+            let (old_perp, new_perp) = (&old.perp_market_cache[i], &new.perp_market_cache[i]);
+            old.price_cache[i].price != new.price_cache[i].price
+                || bank_changed(i)
+                || old_perp.long_funding != new_perp.long_funding
+                || old_perp.short_funding != new_perp.short_funding
+        })
+        .collect::<Vec<usize>>();
+    if bank_changed(QUOTE_INDEX) {
+        indexes.push(QUOTE_INDEX);
+    }
+    indexes
+}
+
+/// Loads the group and cache accounts from `chain_data`
+pub fn load_group_and_cache<'a>(
+    chain_data: &'a ChainData,
+    group_id: &Pubkey,
+    cache_id: &Pubkey,
+) -> anyhow::Result<(&'a MangoGroup, &'a MangoCache)> {
+    let group =
+        load_mango_account_from_chain::<MangoGroup>(DataType::MangoGroup, chain_data, group_id)
+            .context("loading group account")?;
+    let cache =
+        load_mango_account_from_chain::<MangoCache>(DataType::MangoCache, chain_data, cache_id)
+            .context("loading cache account")?;
+    Ok((group, cache))
+}
+
+/// Health of the mango account `account_id`
+///
+/// `chain_data` must contain the group, cache and account as well as all the
+/// OpenOrders accounts in the account's margin basket.
+pub fn compute_health(
+    chain_data: &ChainData,
+    group_id: &Pubkey,
+    cache_id: &Pubkey,
+    account_id: &Pubkey,
+    staleness_limits: &StalenessLimits,
+) -> anyhow::Result<Health> {
+    let (group, cache) = load_group_and_cache(chain_data, group_id, cache_id)?;
+    let account = load_mango_account_from_chain::<MangoAccount>(
+        DataType::MangoAccount,
+        chain_data,
+        account_id,
+    )
+    .context("loading account")?;
+    let oos = get_open_orders(chain_data, group, account).context("loading open orders")?;
+    let slots = write_slot_range(chain_data, group, cache_id, account_id, account)?;
+    check_health(group, cache, account, &oos, staleness_limits, slots).context("computing health")
+}
+
+/// The largest liquidation currently possible on the mango account `account_id`,
+/// with the liquidator's estimated profit
+///
+/// `health` must have been computed from the same `chain_data`. This is separate
+/// from compute_health because it's only needed for candidates. Accounts that
+/// the mango program won't liquidate yet, like early candidates, get None.
+pub fn suggest_liquidation(
+    chain_data: &ChainData,
+    group_id: &Pubkey,
+    cache_id: &Pubkey,
+    account_id: &Pubkey,
+    health: &Health,
+) -> anyhow::Result<Option<(LiquidationSuggestion, I80F48)>> {
+    let (group, cache) = load_group_and_cache(chain_data, group_id, cache_id)?;
+    let account = load_mango_account_from_chain::<MangoAccount>(
+        DataType::MangoAccount,
+        chain_data,
+        account_id,
+    )
+    .context("loading account")?;
+    let liquidatable = health.assets < health.liabilities || health.being_liquidated;
+    let suggestion = best_liquidation(group, cache, account, health.init_health, liquidatable)?;
+    Ok(suggestion.map(|suggestion| {
+        let profit = estimated_profit(group, cache, &suggestion);
+        (suggestion, profit)
+    }))
+}
+
+/// The tokens the mango account `account_id` has deposits or borrows in
+pub fn token_balances(
+    chain_data: &ChainData,
+    group_id: &Pubkey,
+    cache_id: &Pubkey,
+    account_id: &Pubkey,
+) -> anyhow::Result<Vec<TokenBalance>> {
+    let (group, cache) = load_group_and_cache(chain_data, group_id, cache_id)?;
+    let account = load_mango_account_from_chain::<MangoAccount>(
+        DataType::MangoAccount,
+        chain_data,
+        account_id,
+    )
+    .context("loading account")?;
+    let mut balances = vec![];
+    for i in (0..group.num_oracles).chain(std::iter::once(QUOTE_INDEX)) {
+        if group.tokens[i].mint == Pubkey::default() {
+            continue;
+        }
+        let bank_cache = &cache.root_bank_cache[i];
+        let deposit = account.get_native_deposit(bank_cache, i)?;
+        let borrow = account.get_native_borrow(bank_cache, i)?;
+        if deposit.is_zero() && borrow.is_zero() {
+            continue;
+        }
+        balances.push(TokenBalance {
+            token_index: i,
+            mint: group.tokens[i].mint,
+            deposit,
+            borrow,
+        });
+    }
+    Ok(balances)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, bytemuck::Zeroable};
+
+    fn group(num_oracles: usize) -> MangoGroup {
+        let mut group = MangoGroup::zeroed();
+        group.num_oracles = num_oracles;
+        group
+    }
+
+    fn num(value: f64) -> I80F48 {
+        I80F48::from_num(value)
+    }
+
+    fn assert_close(actual: I80F48, expected: f64) {
+        assert!(
+            (actual - num(expected)).abs() < num(0.0001),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    /// Token 0 with price 2, a perp market 0 with base lots of 10 and the
+    /// quote token, all with liquidation fees of 25%
+    fn liquidation_fixture() -> (MangoGroup, MangoCache, MangoAccount) {
+        let mut group = group(1);
+        for i in [0, QUOTE_INDEX] {
+            group.tokens[i].mint = Pubkey::new_unique();
+        }
+        let spot = &mut group.spot_markets[0];
+        spot.init_asset_weight = num(0.5);
+        spot.init_liab_weight = num(1.5);
+        spot.liquidation_fee = num(0.25);
+        let perp = &mut group.perp_markets[0];
+        perp.perp_market = Pubkey::new_unique();
+        perp.base_lot_size = 10;
+        perp.init_asset_weight = num(0.5);
+        perp.init_liab_weight = num(1.5);
+        perp.liquidation_fee = num(0.25);
+
+        let mut cache = MangoCache::zeroed();
+        cache.price_cache[0].price = num(2.0);
+        for i in [0, QUOTE_INDEX] {
+            cache.root_bank_cache[i].deposit_index = ONE_I80F48;
+            cache.root_bank_cache[i].borrow_index = ONE_I80F48;
+        }
+
+        (group, cache, MangoAccount::zeroed())
+    }
+
+    /// Token 0 deposits against quote borrows: each liab token repaid gains
+    /// 1 - 0.5 * 1.25 = 0.375 init health and costs 1.25 / 2 asset tokens
+    fn token_transfer(
+        init_health: f64,
+        deposit: f64,
+        borrow: f64,
+    ) -> Option<(I80F48, I80F48, I80F48)> {
+        let (group, cache, mut account) = liquidation_fixture();
+        account.deposits[0] = num(deposit);
+        account.borrows[QUOTE_INDEX] = num(borrow);
+        let suggestion =
+            token_and_token_suggestion(&group, &cache, &account, num(init_health), 0, QUOTE_INDEX)
+                .unwrap()?;
+        let profit = estimated_profit(&group, &cache, &suggestion);
+        match suggestion {
+            LiquidationSuggestion::TokenAndToken {
+                max_liab_transfer,
+                asset_transfer,
+                ..
+            } => Some((max_liab_transfer, asset_transfer, profit)),
+            _ => panic!("expected a token and token liquidation"),
+        }
+    }
+
+    #[test]
+    fn token_and_token_limited_by_deficit() {
+        let (liab, asset, _) = token_transfer(-3.0, 100.0, 100.0).unwrap();
+        assert_close(liab, 8.0);
+        assert_close(asset, 5.0);
+    }
+
+    #[test]
+    fn token_and_token_limited_by_borrow() {
+        let (liab, asset, _) = token_transfer(-300.0, 100.0, 10.0).unwrap();
+        assert_close(liab, 10.0);
+        assert_close(asset, 6.25);
+    }
+
+    #[test]
+    fn token_and_token_limited_by_asset() {
+        let (liab, asset, _) = token_transfer(-300.0, 5.0, 1000.0).unwrap();
+        assert_close(liab, 8.0);
+        assert_close(asset, 5.0);
+    }
+
+    #[test]
+    fn token_and_token_profit_is_the_fee() {
+        // the liquidator pays 8 quote and receives 5 tokens worth 10
+        let (liab, _, profit) = token_transfer(-3.0, 100.0, 100.0).unwrap();
+        assert_close(liab, 8.0);
+        assert_close(profit, 8.0 * 0.25);
+    }
+
+    #[test]
+    fn token_and_token_without_health_gain() {
+        let (mut group, cache, mut account) = liquidation_fixture();
+        group.spot_markets[0].init_asset_weight = ONE_I80F48;
+        account.deposits[0] = num(100.0);
+        account.borrows[QUOTE_INDEX] = num(100.0);
+        let suggestion =
+            token_and_token_suggestion(&group, &cache, &account, num(-3.0), 0, QUOTE_INDEX);
+        assert!(suggestion.unwrap().is_none());
+    }
+
+    /// Each lot taken over is worth 20 and gains 20 * 0.25 = 5 init health,
+    /// for longs and shorts
+    fn perp_transfer(init_health: f64, base_position: i64) -> Option<(i64, i64, I80F48)> {
+        let (group, cache, mut account) = liquidation_fixture();
+        account.perp_accounts[0].base_position = base_position;
+        let suggestion = perp_market_suggestion(&group, &cache, &account, num(init_health), 0)?;
+        let profit = estimated_profit(&group, &cache, &suggestion);
+        match suggestion {
+            LiquidationSuggestion::PerpMarket {
+                base_position,
+                max_base_transfer,
+                ..
+            } => Some((base_position, max_base_transfer, profit)),
+            _ => panic!("expected a perp market liquidation"),
+        }
+    }
+
+    #[test]
+    fn perp_market_long() {
+        // 2.4 lots are needed, rounded up
+        assert_eq!(
+            perp_transfer(-12.0, 10).map(|(b, t, _)| (b, t)),
+            Some((10, 3))
+        );
+        assert_eq!(
+            perp_transfer(-12.0, 2).map(|(b, t, _)| (b, t)),
+            Some((2, 2))
+        );
+    }
+
+    #[test]
+    fn perp_market_short() {
+        assert_eq!(
+            perp_transfer(-12.0, -10).map(|(b, t, _)| (b, t)),
+            Some((-10, 3))
+        );
+        assert_eq!(
+            perp_transfer(-12.0, -2).map(|(b, t, _)| (b, t)),
+            Some((-2, 2))
+        );
+    }
+
+    #[test]
+    fn perp_market_profit_is_the_fee() {
+        // 3 lots of 10 at a price of 2
+        let (_, transfer, profit) = perp_transfer(-12.0, -10).unwrap();
+        assert_eq!(transfer, 3);
+        assert_close(profit, 60.0 * 0.25);
+    }
+
+    #[test]
+    fn perp_market_without_health_gain() {
+        assert!(perp_transfer(-12.0, 0).is_none());
+        let (mut group, cache, mut account) = liquidation_fixture();
+        group.perp_markets[0].init_asset_weight = num(0.75);
+        account.perp_accounts[0].base_position = 10;
+        assert!(perp_market_suggestion(&group, &cache, &account, num(-12.0), 0).is_none());
+    }
+
+    #[test]
+    fn best_liquidation_skips_same_token_pairs() {
+        let (group, cache, mut account) = liquidation_fixture();
+        account.deposits[0] = num(1000.0);
+        account.borrows[0] = num(1000.0);
+        let best = best_liquidation(&group, &cache, &account, num(-3.0), true).unwrap();
+        assert!(best.is_none());
+
+        account.borrows[QUOTE_INDEX] = num(100.0);
+        let best = best_liquidation(&group, &cache, &account, num(-3.0), true).unwrap();
+        assert!(matches!(
+            best,
+            Some(LiquidationSuggestion::TokenAndToken {
+                asset_index: 0,
+                liab_index: QUOTE_INDEX,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn best_liquidation_prefers_the_larger_value() {
+        let (group, cache, mut account) = liquidation_fixture();
+        // at most 100 against perp lots worth 200
+        account.deposits[0] = num(100.0);
+        account.borrows[QUOTE_INDEX] = num(100.0);
+        account.perp_accounts[0].base_position = -10;
+        let best = best_liquidation(&group, &cache, &account, num(-3.0), true).unwrap();
+        assert!(matches!(
+            best,
+            Some(LiquidationSuggestion::PerpMarket {
+                market_index: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn best_liquidation_needs_a_liquidatable_account() {
+        let (group, cache, mut account) = liquidation_fixture();
+        account.deposits[0] = num(100.0);
+        account.borrows[QUOTE_INDEX] = num(100.0);
+        let best = best_liquidation(&group, &cache, &account, num(-3.0), false).unwrap();
+        assert!(best.is_none());
+        let best = best_liquidation(&group, &cache, &account, ZERO_I80F48, true).unwrap();
+        assert!(best.is_none());
+    }
+
+    #[test]
+    fn changed_cache_entries_ignores_update_times() {
+        let group = group(3);
+        let old = MangoCache::zeroed();
+        let mut new = old;
+        new.price_cache[0].last_update = 10;
+        new.root_bank_cache[1].last_update = 10;
+        new.perp_market_cache[2].last_update = 10;
+        new.root_bank_cache[QUOTE_INDEX].last_update = 10;
+
+        assert!(changed_cache_entries(&group, &old, &new).is_empty());
+    }
+
+    #[test]
+    fn changed_cache_entries_finds_prices_banks_and_funding() {
+        let group = group(4);
+        let old = MangoCache::zeroed();
+        let mut new = old;
+        new.price_cache[0].price = I80F48::from_num(2);
+        new.root_bank_cache[1].borrow_index = I80F48::from_num(1.1);
+        new.perp_market_cache[3].short_funding = I80F48::from_num(-3);
+
+        assert_eq!(changed_cache_entries(&group, &old, &new), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn changed_cache_entries_quote_bank_and_unused_markets() {
+        let group = group(2);
+        let old = MangoCache::zeroed();
+        let mut new = old;
+        // beyond num_oracles, not used by the group
+        new.price_cache[5].price = I80F48::from_num(2);
+        new.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1.1);
+
+        assert_eq!(changed_cache_entries(&group, &old, &new), vec![QUOTE_INDEX]);
+    }
+}
@@ -0,0 +1,79 @@
+use {
+    crate::chain_data::ChainData,
+    crate::health::{Health, LiquidationSuggestion, TokenBalance},
+    crate::{mango_v3, Config, ProgramVersion},
+    fixed::types::I80F48,
+    solana_sdk::account::AccountSharedData,
+    solana_sdk::pubkey::Pubkey,
+    std::sync::Arc,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccountKind {
+    /// An account whose health is tracked
+    Health,
+    /// A change to this account can affect the health of all tracked accounts
+    Global,
+}
+
+#[derive(Debug, Default)]
+pub struct PriceStaleness {
+    /// age of the oldest price
+    pub max_age_secs: u64,
+    /// number of prices that are older than allowed
+    pub stale_count: u64,
+}
+
+/// Program specific logic for identifying accounts and computing their health
+///
+/// Everything outside of the implementations should work for any program version.
+pub trait HealthSource: Send + Sync {
+    /// Classify an account write, None for accounts that don't matter
+    fn is_interesting_account(
+        &self,
+        pubkey: &Pubkey,
+        account: &AccountSharedData,
+    ) -> Option<AccountKind>;
+
+    /// Other accounts that need to be available to compute the health of `account`,
+    /// like serum OpenOrders accounts
+    fn tracked_keys(&self, account: &AccountSharedData) -> Vec<Pubkey>;
+
+    fn compute_health(&self, chain_data: &ChainData, pubkey: &Pubkey) -> anyhow::Result<Health>;
+
+    /// The largest liquidation currently possible on `pubkey` and the liquidator's
+    /// estimated profit, given its `health` computed from the same `chain_data`
+    fn liquidation(
+        &self,
+        chain_data: &ChainData,
+        pubkey: &Pubkey,
+        health: &Health,
+    ) -> anyhow::Result<Option<(LiquidationSuggestion, I80F48)>>;
+
+    /// The tokens `pubkey` has deposits or borrows in
+    fn balances(
+        &self,
+        chain_data: &ChainData,
+        pubkey: &Pubkey,
+    ) -> anyhow::Result<Vec<TokenBalance>>;
+
+    fn price_staleness(&self, chain_data: &ChainData) -> anyhow::Result<PriceStaleness>;
+
+    /// Indexes of the prices the health of `pubkey` depends on, None if unknown
+    fn price_dependencies(&self, chain_data: &ChainData, pubkey: &Pubkey) -> Option<Vec<usize>>;
+
+    /// Indexes of the prices that differ between two versions of a Global account,
+    /// None if all accounts need to be checked
+    fn changed_prices(
+        &self,
+        chain_data: &ChainData,
+        old: &AccountSharedData,
+        new: &AccountSharedData,
+    ) -> Option<Vec<usize>>;
+}
+
+pub fn from_config(config: &Config) -> anyhow::Result<Arc<dyn HealthSource>> {
+    Ok(match config.mango_program_version {
+        ProgramVersion::V3 => Arc::new(mango_v3::MangoV3::new(config)?),
+    })
+}
@@ -1,174 +1,251 @@
 use {
     crate::chain_data::ChainData,
+    crate::health::Health,
+    crate::health_source::HealthSource,
     crate::websocket_sink::{HealthInfo, LiquidationCanditate},
-    crate::Config,
-    anyhow::Context,
+    crate::{metrics, Config, StaleOracleHandling},
     fixed::types::I80F48,
     log::*,
-    mango::state::{
-        DataType, HealthCache, HealthType, MangoAccount, MangoCache, MangoGroup, UserActiveAssets,
-        MAX_PAIRS,
-    },
-    mango_common::Loadable,
-    solana_sdk::account::{AccountSharedData, ReadableAccount},
+    rayon::prelude::*,
     solana_sdk::pubkey::Pubkey,
-    std::collections::HashSet,
+    std::collections::{HashMap, HashSet},
+    std::sync::Arc,
+    std::time::{Duration, Instant},
     tokio::sync::broadcast,
+    tokio::task::JoinHandle,
 };
 
-// FUTURE: It'd be very nice if I could map T to the DataType::T constant!
-pub fn load_mango_account<T: Loadable + Sized>(
-    data_type: DataType,
-    account: &AccountSharedData,
-) -> anyhow::Result<&T> {
-    let data = account.data();
-    let data_type_int = data_type as u8;
-    if data.len() != std::mem::size_of::<T>() {
-        anyhow::bail!(
-            "bad account size for {}: {} expected {}",
-            data_type_int,
-            data.len(),
-            std::mem::size_of::<T>()
-        );
+/// The accounts that are currently candidates
+///
+/// Used to send a different message for newly liquidatable accounts and
+/// accounts that are still liquidatable but not fresh anymore.
+pub struct Candidates {
+    current: HashSet<Pubkey>,
+    /// when each account last became a candidate or stopped being one
+    last_change: HashMap<Pubkey, Instant>,
+    /// accounts that were bankrupt when their health was last checked
+    bankrupt: HashSet<Pubkey>,
+    /// highest slot of the data of the most recent health check of each account
+    checked_slot: HashMap<Pubkey, u64>,
+    metric_bankrupt: metrics::MetricU64,
+}
+
+impl Candidates {
+    pub fn new(metrics: &metrics::Metrics) -> Self {
+        Self {
+            current: HashSet::new(),
+            last_change: HashMap::new(),
+            bankrupt: HashSet::new(),
+            checked_slot: HashMap::new(),
+            metric_bankrupt: metrics.register_u64("bankrupt_accounts".into()),
+        }
     }
-    if data[0] != data_type_int {
-        anyhow::bail!(
-            "unexpected data type for {}, got {}",
-            data_type_int,
-            data[0]
-        );
+
+    pub fn contains(&self, pubkey: &Pubkey) -> bool {
+        self.current.contains(pubkey)
     }
-    return Ok(Loadable::load_from_bytes(&data).expect("always Ok"));
-}
 
-fn load_mango_account_from_chain<'a, T: Loadable + Sized>(
-    data_type: DataType,
-    chain_data: &'a ChainData,
-    pubkey: &Pubkey,
-) -> anyhow::Result<&'a T> {
-    load_mango_account::<T>(
-        data_type,
-        chain_data
-            .account(pubkey)
-            .context("retrieving account from chain")?,
-    )
-}
+    /// Remember that `pubkey` was checked with data up to `slot`, false if a
+    /// check with newer data was already processed
+    fn record_check(&mut self, pubkey: &Pubkey, slot: u64) -> bool {
+        let checked_slot = self.checked_slot.entry(*pubkey).or_insert(0);
+        if slot < *checked_slot {
+            return false;
+        }
+        *checked_slot = slot;
+        true
+    }
 
-pub fn load_open_orders_account(
-    account: &AccountSharedData,
-) -> anyhow::Result<&serum_dex::state::OpenOrders> {
-    let data = account.data();
-    let expected_size = 12 + std::mem::size_of::<serum_dex::state::OpenOrders>();
-    if data.len() != expected_size {
-        anyhow::bail!(
-            "bad open orders account size: {} expected {}",
-            data.len(),
-            expected_size
-        );
+    /// Forget changes of non-candidates that can't hold back another change anymore
+    fn prune(&mut self, now: Instant, min_interval: Duration) {
+        let current = &self.current;
+        self.last_change.retain(|pubkey, last| {
+            current.contains(pubkey) || now.duration_since(*last) < min_interval
+        });
     }
-    if &data[0..5] != "serum".as_bytes() {
-        anyhow::bail!("unexpected open orders account prefix");
+
+    fn can_change(&self, pubkey: &Pubkey, now: Instant, min_interval: Duration) -> bool {
+        self.last_change
+            .get(pubkey)
+            .map(|last| now.duration_since(*last) >= min_interval)
+            .unwrap_or(true)
+    }
+
+    fn set(&mut self, pubkey: &Pubkey, is_candidate: bool, now: Instant) {
+        if is_candidate {
+            self.current.insert(*pubkey);
+        } else {
+            self.current.remove(pubkey);
+        }
+        self.last_change.insert(*pubkey, now);
+    }
+
+    fn set_bankrupt(&mut self, pubkey: &Pubkey, bankrupt: bool) {
+        let changed = if bankrupt {
+            self.bankrupt.insert(*pubkey)
+        } else {
+            self.bankrupt.remove(pubkey)
+        };
+        if changed {
+            self.metric_bankrupt.set(self.bankrupt.len() as u64);
+        }
     }
-    Ok(bytemuck::from_bytes::<serum_dex::state::OpenOrders>(
-        &data[5..data.len() - 7],
-    ))
 }
 
-fn get_open_orders<'a>(
-    chain_data: &'a ChainData,
-    group: &MangoGroup,
-    account: &'a MangoAccount,
-) -> anyhow::Result<Vec<Option<&'a serum_dex::state::OpenOrders>>> {
-    let mut unpacked = vec![None; MAX_PAIRS];
-    for i in 0..group.num_oracles {
-        if account.in_margin_basket[i] {
-            let oo = chain_data.account(&account.spot_open_orders[i])?;
-            unpacked[i] = Some(load_open_orders_account(oo)?);
+/// How urgent liquidating an account is, higher is more urgent
+///
+/// The estimated profit if the account can be liquidated, otherwise how far it
+/// is below the threshold, weighted by its liabilities.
+fn priority(health: &Health, estimated_profit: Option<I80F48>, threshold: f64) -> f64 {
+    match estimated_profit {
+        Some(profit) => profit.to_num::<f64>(),
+        None => {
+            let deficit = threshold - health.health_fraction.to_num::<f64>();
+            deficit.max(0.0) * health.liabilities.to_num::<f64>()
         }
     }
-    Ok(unpacked)
 }
 
-#[derive(Debug)]
-struct Health {
-    candidate: bool,
-    being_liquidated: bool,
-    health_fraction: I80F48, // always maint
-    assets: I80F48,          // always maint
-    liabilities: I80F48,     // always maint
+/// Health of each of `accounts`, computed in parallel on the rayon thread pool
+pub fn compute_healths(
+    health_source: &dyn HealthSource,
+    chain_data: &ChainData,
+    accounts: Vec<Pubkey>,
+) -> Vec<(Pubkey, anyhow::Result<Health>)> {
+    let healths = accounts
+        .par_iter()
+        .map(|pubkey| health_source.compute_health(chain_data, pubkey))
+        .collect::<Vec<_>>();
+    accounts.into_iter().zip(healths).collect()
 }
 
-fn check_health(
-    config: &Config,
-    group: &MangoGroup,
-    cache: &MangoCache,
-    account: &MangoAccount,
-    open_orders: &Vec<Option<&serum_dex::state::OpenOrders>>,
-) -> anyhow::Result<Health> {
-    let assets = UserActiveAssets::new(group, account, vec![]);
-    let mut health_cache = HealthCache::new(assets);
-    health_cache.init_vals_with_orders_vec(group, cache, account, open_orders)?;
-
-    let (assets, liabilities) = health_cache.get_health_components(group, HealthType::Maint);
-    let health_fraction = if liabilities > 0 {
-        assets / liabilities
-    } else {
-        I80F48::MAX
-    };
+/// Healths computed by a check that ran in the background
+pub struct BackgroundResult {
+    /// the copy of the chain data the healths were computed from
+    pub chain_data: ChainData,
+    pub healths: Vec<(Pubkey, anyhow::Result<Health>)>,
+    /// were all accounts checked?
+    pub full: bool,
+    pub wall_time: Duration,
+}
 
-    let still_being_liquidated =
-        account.being_liquidated && health_cache.get_health(group, HealthType::Init) < 0;
+/// Health checks of many accounts that run outside of the main loop
+///
+/// A check runs on the rayon thread pool against its own copy of the chain
+/// data, so new data keeps being processed meanwhile. Only one check runs at
+/// a time, checks requested while it runs are merged and started after it.
+#[derive(Default)]
+pub struct BackgroundChecks {
+    running: Option<JoinHandle<BackgroundResult>>,
+    pending_full: bool,
+    pending: HashSet<Pubkey>,
+}
 
-    let threshold = 1.0 + config.early_candidate_percentage / 100.0;
-    let candidate = health_fraction < threshold || still_being_liquidated;
-
-    Ok(Health {
-        candidate,
-        being_liquidated: still_being_liquidated,
-        health_fraction,
-        assets,
-        liabilities,
-    })
+impl BackgroundChecks {
+    /// Check all accounts with the next check
+    pub fn request_full(&mut self) {
+        self.pending_full = true;
+        self.pending.clear();
+    }
+
+    /// Check `accounts` with the next check
+    pub fn request(&mut self, accounts: impl Iterator<Item = Pubkey>) {
+        if !self.pending_full {
+            self.pending.extend(accounts);
+        }
+    }
+
+    /// Start checking the requested accounts, unless a check is still running
+    pub fn start_pending(
+        &mut self,
+        health_source: &Arc<dyn HealthSource>,
+        chain_data: &ChainData,
+        all_accounts: &HashSet<Pubkey>,
+    ) {
+        if self.running.is_some() || (!self.pending_full && self.pending.is_empty()) {
+            return;
+        }
+        let full = std::mem::take(&mut self.pending_full);
+        let accounts = if full {
+            all_accounts.iter().copied().collect()
+        } else {
+            self.pending.drain().collect()
+        };
+        let health_source = health_source.clone();
+        let chain_data = chain_data.clone();
+        self.running = Some(tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let healths = compute_healths(health_source.as_ref(), &chain_data, accounts);
+            BackgroundResult {
+                chain_data,
+                healths,
+                full,
+                wall_time: start.elapsed(),
+            }
+        }));
+    }
+
+    /// Wait for the running check to finish, forever if there is none
+    pub async fn finished(&mut self) -> anyhow::Result<BackgroundResult> {
+        match &mut self.running {
+            Some(handle) => {
+                let result = handle.await;
+                self.running = None;
+                Ok(result?)
+            }
+            None => std::future::pending().await,
+        }
+    }
 }
 
+/// Check the health of `accounts` and send messages about candidates to `tx`
 pub fn process_accounts<'a>(
     config: &Config,
+    health_source: &dyn HealthSource,
     chain_data: &ChainData,
-    group_id: &Pubkey,
-    cache_id: &Pubkey,
     accounts: impl Iterator<Item = &'a Pubkey>,
-    current_candidates: &mut HashSet<Pubkey>,
+    current_candidates: &mut Candidates,
     tx: &broadcast::Sender<LiquidationCanditate>,
-) -> anyhow::Result<()> {
-    let group =
-        load_mango_account_from_chain::<MangoGroup>(DataType::MangoGroup, chain_data, group_id)
-            .context("loading group account")?;
-    let cache =
-        load_mango_account_from_chain::<MangoCache>(DataType::MangoCache, chain_data, cache_id)
-            .context("loading cache account")?;
-
-    for pubkey in accounts {
-        let account_result = load_mango_account_from_chain::<MangoAccount>(
-            DataType::MangoAccount,
-            chain_data,
-            pubkey,
-        );
-        let account = match account_result {
-            Ok(account) => account,
-            Err(err) => {
-                warn!("could not load account {}: {:?}", pubkey, err);
-                continue;
-            }
-        };
-        let oos = match get_open_orders(chain_data, group, account) {
-            Ok(oos) => oos,
-            Err(err) => {
-                warn!("could not load account {} open orders: {:?}", pubkey, err);
-                continue;
-            }
-        };
+) {
+    let healths = compute_healths(health_source, chain_data, accounts.copied().collect());
+    process_healths(
+        config,
+        health_source,
+        chain_data,
+        &healths,
+        current_candidates,
+        tx,
+    );
+}
+
+/// Send messages about candidates among the accounts in `healths` to `tx`
+///
+/// `chain_data` must be what the healths were computed from. Start messages
+/// are sent first, those with an estimated profit before the others and each
+/// in order of descending priority, then the other messages in the order of
+/// `healths`.
+pub fn process_healths(
+    config: &Config,
+    health_source: &dyn HealthSource,
+    chain_data: &ChainData,
+    healths: &[(Pubkey, anyhow::Result<Health>)],
+    current_candidates: &mut Candidates,
+    tx: &broadcast::Sender<LiquidationCanditate>,
+) {
+    let start = Instant::now();
+    // Accounts close to the threshold would flip between being a candidate and
+    // not being one all the time. Becoming one requires being a bit below the
+    // threshold, stopping requires being a bit above it.
+    let threshold = 1.0 + config.early_candidate_percentage / 100.0;
+    let start_threshold = threshold - config.candidate_hysteresis_percentage / 100.0;
+    let stop_threshold = threshold + config.candidate_hysteresis_percentage / 100.0;
+    let min_change_interval = Duration::from_secs(config.candidate_min_change_interval_secs);
+    current_candidates.prune(start, min_change_interval);
 
-        let info = match check_health(config, group, cache, account, &oos) {
+    let mut starts = vec![];
+    let mut messages = vec![];
+    for (pubkey, health) in healths.iter() {
+        let info = match health {
             Ok(d) => d,
             Err(err) => {
                 warn!("error computing health of {}: {:?}", pubkey, err);
@@ -176,36 +253,426 @@ pub fn process_accounts<'a>(
             }
         };
 
+        // Checks in the background run on a snapshot, their results may be
+        // older than those of a check that finished in the meantime.
+        if !current_candidates.record_check(pubkey, info.max_slot) {
+            debug!("skipping outdated health of account {}", pubkey);
+            continue;
+        }
+
+        current_candidates.set_bankrupt(pubkey, info.bankrupt);
+        if info.bankrupt {
+            debug!("account {} is bankrupt", pubkey);
+        }
+
+        // Candidates whose health can't be trusted anymore get a Stop, so that
+        // clients don't keep acting on them.
+        let suppressed =
+            info.stale_oracle && config.stale_oracle_handling == StaleOracleHandling::Suppress;
+        if suppressed && !current_candidates.contains(pubkey) {
+            debug!("skipping account {} due to stale prices", pubkey);
+            continue;
+        }
+
+        let was_candidate = current_candidates.contains(pubkey);
+        let mut is_candidate = if was_candidate {
+            info.health_fraction < stop_threshold || info.being_liquidated
+        } else {
+            info.health_fraction < start_threshold || info.being_liquidated
+        };
+        if suppressed {
+            debug!("stopping candidate {} due to stale prices", pubkey);
+            is_candidate = false;
+        } else if info.bankrupt {
+            // bankruptcies are always reported, they don't go away by themselves
+            is_candidate = true;
+        } else if is_candidate != was_candidate
+            && !current_candidates.can_change(pubkey, start, min_change_interval)
+        {
+            debug!(
+                "account {} changed candidate state too recently, not notifying",
+                pubkey
+            );
+            is_candidate = was_candidate;
+        }
+        if !is_candidate && !was_candidate {
+            continue;
+        }
+
+        // only candidates get a liquidation suggestion
+        let liquidation = if is_candidate {
+            health_source
+                .liquidation(chain_data, pubkey, info)
+                .unwrap_or_else(|err| {
+                    warn!("error computing liquidation of {}: {:?}", pubkey, err);
+                    None
+                })
+        } else {
+            None
+        };
+        let estimated_profit = liquidation.as_ref().map(|(_, profit)| *profit);
+        let balances = health_source
+            .balances(chain_data, pubkey)
+            .unwrap_or_else(|err| {
+                warn!("error loading balances of {}: {:?}", pubkey, err);
+                vec![]
+            });
         let health_info = HealthInfo {
             account: pubkey.clone(),
+            slot: chain_data.newest_processed_slot(),
             being_liquidated: info.being_liquidated,
             health_fraction: info.health_fraction,
             assets: info.assets,
             liabilities: info.liabilities,
+            liquidation: liquidation.map(|(suggestion, _)| suggestion),
+            estimated_profit,
+            priority: priority(info, estimated_profit, threshold),
+            bankrupt: info.bankrupt,
+            stale_oracle: info.stale_oracle,
+            min_slot: info.min_slot,
+            max_slot: info.max_slot,
+            balances,
         };
 
-        let is_candidate = info.candidate;
-        let was_candidate = current_candidates.contains(pubkey);
         if is_candidate && !was_candidate {
             info!("account {} is a new candidate", pubkey);
-            current_candidates.insert(pubkey.clone());
-            let _ = tx.send(LiquidationCanditate::Start {
+            current_candidates.set(pubkey, true, start);
+            starts.push(health_info.clone());
+        }
+        if is_candidate && info.bankrupt {
+            messages.push(LiquidationCanditate::Bankruptcy {
                 info: health_info.clone(),
             });
-        }
-        if is_candidate {
-            let _ = tx.send(LiquidationCanditate::Now {
+        } else if is_candidate {
+            messages.push(LiquidationCanditate::Now {
                 info: health_info.clone(),
             });
         }
         if !is_candidate && was_candidate {
             info!("account {} stopped being a candidate", pubkey);
-            current_candidates.remove(pubkey);
-            let _ = tx.send(LiquidationCanditate::Stop {
+            current_candidates.set(pubkey, false, start);
+            messages.push(LiquidationCanditate::Stop {
                 info: health_info.clone(),
             });
         }
     }
 
-    Ok(())
+    // when many accounts become candidates at once, like after a price drop,
+    // clients should hear about the most valuable ones first; profits and
+    // deficits aren't comparable, accounts that can be liquidated come first
+    starts.sort_by(|a, b| {
+        b.estimated_profit
+            .is_some()
+            .cmp(&a.estimated_profit.is_some())
+            .then_with(|| {
+                b.priority
+                    .partial_cmp(&a.priority)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    });
+    for info in starts {
+        let _ = tx.send(LiquidationCanditate::Start { info });
+    }
+    for message in messages {
+        let _ = tx.send(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::health::{LiquidationSuggestion, TokenBalance},
+        crate::health_source::{AccountKind, PriceStaleness},
+        solana_sdk::account::AccountSharedData,
+    };
+
+    /// Only provides liquidations, with the given profits
+    #[derive(Default)]
+    struct StubHealthSource {
+        profits: HashMap<Pubkey, I80F48>,
+    }
+
+    impl HealthSource for StubHealthSource {
+        fn is_interesting_account(&self, _: &Pubkey, _: &AccountSharedData) -> Option<AccountKind> {
+            unimplemented!()
+        }
+
+        fn tracked_keys(&self, _: &AccountSharedData) -> Vec<Pubkey> {
+            unimplemented!()
+        }
+
+        fn compute_health(&self, _: &ChainData, _: &Pubkey) -> anyhow::Result<Health> {
+            unimplemented!()
+        }
+
+        fn liquidation(
+            &self,
+            _: &ChainData,
+            pubkey: &Pubkey,
+            _: &Health,
+        ) -> anyhow::Result<Option<(LiquidationSuggestion, I80F48)>> {
+            let suggestion = LiquidationSuggestion::PerpMarket {
+                market_index: 0,
+                perp_market: Pubkey::default(),
+                base_position: 1,
+                max_base_transfer: 1,
+            };
+            Ok(self.profits.get(pubkey).map(|profit| (suggestion, *profit)))
+        }
+
+        fn balances(&self, _: &ChainData, _: &Pubkey) -> anyhow::Result<Vec<TokenBalance>> {
+            Ok(vec![])
+        }
+
+        fn price_staleness(&self, _: &ChainData) -> anyhow::Result<PriceStaleness> {
+            unimplemented!()
+        }
+
+        fn price_dependencies(&self, _: &ChainData, _: &Pubkey) -> Option<Vec<usize>> {
+            unimplemented!()
+        }
+
+        fn changed_prices(
+            &self,
+            _: &ChainData,
+            _: &AccountSharedData,
+            _: &AccountSharedData,
+        ) -> Option<Vec<usize>> {
+            unimplemented!()
+        }
+    }
+
+    /// Candidates below a health fraction of 1.0, without hysteresis or minimum interval
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            rpc_ws_url = ""
+            rpc_http_url = ""
+            mango_program_id = ""
+            mango_group_id = ""
+            mango_cache_id = ""
+            mango_signer_id = ""
+            serum_program_id = ""
+            snapshot_interval_secs = 300
+            websocket_server_bind_address = ""
+            parallel_rpc_requests = 1
+            get_multiple_accounts_count = 100
+            early_candidate_percentage = 0.0
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn health(health_fraction: f64) -> Health {
+        Health {
+            being_liquidated: false,
+            health_fraction: I80F48::from_num(health_fraction),
+            assets: I80F48::from_num(health_fraction * 100.0),
+            liabilities: I80F48::from_num(100),
+            init_health: I80F48::from_num(0),
+            bankrupt: false,
+            stale_oracle: false,
+            min_slot: 0,
+            max_slot: 0,
+        }
+    }
+
+    struct Fixture {
+        config: Config,
+        health_source: StubHealthSource,
+        chain_data: ChainData,
+        candidates: Candidates,
+    }
+
+    impl Fixture {
+        fn new(config: Config) -> Self {
+            let metrics = metrics::start();
+            Self {
+                config,
+                health_source: StubHealthSource::default(),
+                chain_data: ChainData::new(&metrics),
+                candidates: Candidates::new(&metrics),
+            }
+        }
+
+        /// The messages sent for a health check with `healths`, like ("start", account)
+        fn check(&mut self, healths: Vec<(Pubkey, Health)>) -> Vec<(&'static str, Pubkey)> {
+            let healths = healths
+                .into_iter()
+                .map(|(pubkey, health)| (pubkey, Ok(health)))
+                .collect::<Vec<_>>();
+            let (tx, mut rx) = broadcast::channel(100);
+            process_healths(
+                &self.config,
+                &self.health_source,
+                &self.chain_data,
+                &healths,
+                &mut self.candidates,
+                &tx,
+            );
+            let mut messages = vec![];
+            while let Ok(message) = rx.try_recv() {
+                messages.push(match message {
+                    LiquidationCanditate::Start { info } => ("start", info.account),
+                    LiquidationCanditate::Now { info } => ("now", info.account),
+                    LiquidationCanditate::Bankruptcy { info } => ("bankruptcy", info.account),
+                    LiquidationCanditate::Stop { info } => ("stop", info.account),
+                    LiquidationCanditate::Shutdown => ("shutdown", Pubkey::default()),
+                });
+            }
+            messages
+        }
+
+        /// Pretend that the minimum interval since the last change of `pubkey` passed
+        fn forget_change_time(&mut self, pubkey: &Pubkey) {
+            self.candidates.last_change.remove(pubkey);
+        }
+    }
+
+    #[tokio::test]
+    async fn hysteresis_edges() {
+        let mut fixture = Fixture::new(Config {
+            candidate_hysteresis_percentage: 10.0,
+            ..config()
+        });
+        let a = Pubkey::new_unique();
+
+        // starting needs < 0.9, stopping needs >= 1.1
+        assert!(fixture.check(vec![(a, health(0.95))]).is_empty());
+        assert_eq!(
+            fixture.check(vec![(a, health(0.85))]),
+            vec![("start", a), ("now", a)]
+        );
+        assert_eq!(fixture.check(vec![(a, health(1.05))]), vec![("now", a)]);
+        assert_eq!(fixture.check(vec![(a, health(1.15))]), vec![("stop", a)]);
+        assert!(fixture.check(vec![(a, health(0.95))]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_threshold_without_hysteresis() {
+        let mut fixture = Fixture::new(config());
+        let a = Pubkey::new_unique();
+
+        assert!(fixture.check(vec![(a, health(1.0))]).is_empty());
+        assert_eq!(
+            fixture.check(vec![(a, health(0.99))]),
+            vec![("start", a), ("now", a)]
+        );
+        assert_eq!(fixture.check(vec![(a, health(1.0))]), vec![("stop", a)]);
+    }
+
+    #[tokio::test]
+    async fn min_interval_holds_back_changes() {
+        let mut fixture = Fixture::new(Config {
+            candidate_min_change_interval_secs: 60,
+            ..config()
+        });
+        let a = Pubkey::new_unique();
+
+        assert_eq!(
+            fixture.check(vec![(a, health(0.9))]),
+            vec![("start", a), ("now", a)]
+        );
+        // still reported as a candidate until the interval passed
+        assert_eq!(fixture.check(vec![(a, health(1.2))]), vec![("now", a)]);
+        fixture.forget_change_time(&a);
+        assert_eq!(fixture.check(vec![(a, health(1.2))]), vec![("stop", a)]);
+        assert!(fixture.check(vec![(a, health(0.9))]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn bankrupt_account_bypasses_min_interval() {
+        let mut fixture = Fixture::new(Config {
+            candidate_min_change_interval_secs: 60,
+            ..config()
+        });
+        let a = Pubkey::new_unique();
+
+        fixture.check(vec![(a, health(0.9))]);
+        fixture.forget_change_time(&a);
+        assert_eq!(fixture.check(vec![(a, health(1.2))]), vec![("stop", a)]);
+
+        let mut bankrupt = health(0.0);
+        bankrupt.bankrupt = true;
+        assert_eq!(
+            fixture.check(vec![(a, bankrupt)]),
+            vec![("start", a), ("bankruptcy", a)]
+        );
+        assert!(fixture.candidates.bankrupt.contains(&a));
+    }
+
+    #[tokio::test]
+    async fn suppressed_candidate_gets_stop() {
+        let mut fixture = Fixture::new(Config {
+            stale_oracle_handling: StaleOracleHandling::Suppress,
+            ..config()
+        });
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        assert_eq!(
+            fixture.check(vec![(a, health(0.9))]),
+            vec![("start", a), ("now", a)]
+        );
+        let mut stale = health(0.9);
+        stale.stale_oracle = true;
+        let mut stale_b = health(0.9);
+        stale_b.stale_oracle = true;
+        assert_eq!(
+            fixture.check(vec![(a, stale), (b, stale_b)]),
+            vec![("stop", a)]
+        );
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_candidates_and_recent_changes() {
+        let mut fixture = Fixture::new(config());
+        let (candidate, recent, old) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let start = Instant::now();
+        let now = start + Duration::from_secs(3600);
+        let candidates = &mut fixture.candidates;
+        candidates.set(&candidate, true, start);
+        candidates.set(&recent, false, now - Duration::from_secs(10));
+        candidates.set(&old, false, start);
+
+        candidates.prune(now, Duration::from_secs(60));
+        let last_change = &candidates.last_change;
+        assert!(last_change.contains_key(&candidate));
+        assert!(last_change.contains_key(&recent));
+        assert!(!last_change.contains_key(&old));
+    }
+
+    #[tokio::test]
+    async fn liquidatable_candidates_start_first() {
+        let mut fixture = Fixture::new(Config {
+            early_candidate_percentage: 10.0,
+            ..config()
+        });
+        let (early, liquidatable, less_early) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        fixture
+            .health_source
+            .profits
+            .insert(liquidatable, I80F48::from_num(1));
+
+        // priorities of 10, 1 and 5
+        let messages = fixture.check(vec![
+            (early, health(1.0)),
+            (liquidatable, health(0.9)),
+            (less_early, health(1.05)),
+        ]);
+        let starts = messages
+            .into_iter()
+            .filter(|(kind, _)| *kind == "start")
+            .map(|(_, pubkey)| pubkey)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![liquidatable, early, less_early]);
+    }
 }
@@ -0,0 +1,185 @@
+pub mod chain_data;
+pub mod cli;
+pub mod health;
+pub mod health_source;
+pub mod healthcheck;
+pub mod mango_v3;
+pub mod metrics;
+pub mod once;
+pub mod persistence;
+pub mod price_dependencies;
+pub mod recording;
+pub mod snapshot_source;
+pub mod status_server;
+pub mod websocket_sink;
+pub mod websocket_source;
+
+use {serde_derive::Deserialize, solana_sdk::pubkey::Pubkey, std::collections::HashMap};
+
+trait AnyhowWrap {
+    type Value;
+    fn map_err_anyhow(self) -> anyhow::Result<Self::Value>;
+}
+
+impl<T, E: std::fmt::Debug> AnyhowWrap for Result<T, E> {
+    type Value = T;
+    fn map_err_anyhow(self) -> anyhow::Result<Self::Value> {
+        self.map_err(|err| anyhow::anyhow!("{:?}", err))
+    }
+}
+
+pub fn now_unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgramVersion {
+    V3,
+}
+
+impl Default for ProgramVersion {
+    fn default() -> Self {
+        ProgramVersion::V3
+    }
+}
+
+/// What to do with accounts whose health depends on stale prices
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleOracleHandling {
+    /// publish as usual, with the stale_oracle flag set
+    Annotate,
+    /// don't publish anything about the account, candidates get a Stop
+    Suppress,
+}
+
+impl Default for StaleOracleHandling {
+    fn default() -> Self {
+        StaleOracleHandling::Annotate
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Config {
+    pub rpc_ws_url: String,
+    pub rpc_http_url: String,
+    #[serde(default)]
+    pub mango_program_version: ProgramVersion,
+    pub mango_program_id: String,
+    pub mango_group_id: String,
+    pub mango_cache_id: String,
+    pub mango_signer_id: String,
+    pub serum_program_id: String,
+    pub snapshot_interval_secs: u64,
+    pub websocket_server_bind_address: String,
+    // how many getMultipleAccounts requests to send in parallel
+    pub parallel_rpc_requests: usize,
+    // typically 100 is the max number for getMultipleAccounts
+    pub get_multiple_accounts_count: usize,
+    pub early_candidate_percentage: f64,
+    // 0 disables the staleness check
+    #[serde(default)]
+    pub oracle_max_staleness_secs: u64,
+    // oracle pubkey -> max staleness in seconds
+    #[serde(default)]
+    pub oracle_max_staleness_overrides: HashMap<String, u64>,
+    #[serde(default)]
+    pub stale_oracle_handling: StaleOracleHandling,
+    // how often to check the health of all accounts, independent of cache updates
+    #[serde(default = "default_full_health_check_interval_secs")]
+    pub full_health_check_interval_secs: u64,
+    // a RUST_LOG style filter, like "info" or "info,liquidatable_accounts_feed=debug"
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    // how long to wait for websocket clients to receive the shutdown message
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
+    // liquidation events are written to at most one of these databases
+    #[serde(default)]
+    pub postgres_connection_string: Option<String>,
+    #[serde(default)]
+    pub sqlite_path: Option<String>,
+    // if set, all received data is recorded to this file, for use with --replay
+    #[serde(default)]
+    pub record_path: Option<String>,
+    // threads for computing account health in parallel, 0 uses one per cpu
+    #[serde(default)]
+    pub health_check_threads: usize,
+    // websocket clients that fall this many messages behind are disconnected
+    #[serde(default = "default_websocket_client_max_lag")]
+    pub websocket_client_max_lag: usize,
+    // serve /healthz and /status on this address, if set
+    #[serde(default)]
+    pub status_server_bind_address: Option<String>,
+    // the status is not ok if no new slot was seen for this long, 0 disables
+    #[serde(default = "default_status_max_slot_age_secs")]
+    pub status_max_slot_age_secs: u64,
+    // the status is not ok if no snapshot was processed for this long, 0 disables
+    #[serde(default = "default_status_max_snapshot_age_secs")]
+    pub status_max_snapshot_age_secs: u64,
+    // accounts become candidates this many percent below the threshold and stop
+    // being candidates this many percent above it
+    #[serde(default)]
+    pub candidate_hysteresis_percentage: f64,
+    // minimum time between an account becoming a candidate and stopping to be one,
+    // and the other way around
+    #[serde(default)]
+    pub candidate_min_change_interval_secs: u64,
+    // PEM files for serving websocket clients over tls, both or neither must be set
+    #[serde(default)]
+    pub websocket_server_tls_cert_path: Option<String>,
+    #[serde(default)]
+    pub websocket_server_tls_key_path: Option<String>,
+}
+
+fn default_full_health_check_interval_secs() -> u64 {
+    60
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    5
+}
+
+fn default_websocket_client_max_lag() -> usize {
+    1000
+}
+
+fn default_status_max_slot_age_secs() -> u64 {
+    60
+}
+
+fn default_status_max_snapshot_age_secs() -> u64 {
+    900
+}
+
+impl Config {
+    /// Copy of self with the settings that can change at runtime taken from `new`
+    pub fn with_reloadable_settings(&self, new: &Config) -> Config {
+        let mut config = self.clone();
+        config.snapshot_interval_secs = new.snapshot_interval_secs;
+        config.early_candidate_percentage = new.early_candidate_percentage;
+        config.oracle_max_staleness_secs = new.oracle_max_staleness_secs;
+        config.oracle_max_staleness_overrides = new.oracle_max_staleness_overrides.clone();
+        config.stale_oracle_handling = new.stale_oracle_handling;
+        config.full_health_check_interval_secs = new.full_health_check_interval_secs;
+        config.log_level = new.log_level.clone();
+        config.shutdown_grace_period_secs = new.shutdown_grace_period_secs;
+        config.status_max_slot_age_secs = new.status_max_slot_age_secs;
+        config.status_max_snapshot_age_secs = new.status_max_snapshot_age_secs;
+        config.candidate_hysteresis_percentage = new.candidate_hysteresis_percentage;
+        config.candidate_min_change_interval_secs = new.candidate_min_change_interval_secs;
+        config
+    }
+}
+
+pub fn encode_address(addr: &Pubkey) -> String {
+    bs58::encode(&addr.to_bytes()).into_string()
+}
@@ -1,22 +1,18 @@
-pub mod chain_data;
-pub mod healthcheck;
-pub mod metrics;
-pub mod snapshot_source;
-pub mod websocket_sink;
-pub mod websocket_source;
-
 use {
-    crate::chain_data::*,
+    clap::Parser,
+    liquidatable_accounts_feed::{
+        chain_data::*,
+        cli,
+        health_source::{self, AccountKind},
+        healthcheck, metrics, now_unix_timestamp, once, persistence,
+        price_dependencies::PriceDependencies,
+        recording, snapshot_source, status_server, websocket_sink, websocket_source,
+    },
     log::*,
-    mango::state::{DataType, MangoAccount},
-    mango_common::Loadable,
-    serde_derive::Deserialize,
-    solana_sdk::account::{AccountSharedData, ReadableAccount},
-    solana_sdk::pubkey::Pubkey,
-    std::collections::HashSet,
-    std::fs::File,
-    std::io::Read,
-    std::str::FromStr,
+    solana_sdk::{account::AccountSharedData, pubkey::Pubkey},
+    std::collections::{HashMap, HashSet},
+    tokio::signal::unix::{signal, SignalKind},
+    tokio::sync::watch,
 };
 
 // jemalloc seems to be better at keeping the memory footprint reasonable over
@@ -24,114 +20,125 @@ use {
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-trait AnyhowWrap {
-    type Value;
-    fn map_err_anyhow(self) -> anyhow::Result<Self::Value>;
-}
-
-impl<T, E: std::fmt::Debug> AnyhowWrap for Result<T, E> {
-    type Value = T;
-    fn map_err_anyhow(self) -> anyhow::Result<Self::Value> {
-        self.map_err(|err| anyhow::anyhow!("{:?}", err))
-    }
-}
-
-#[derive(Clone, Debug, Deserialize)]
-pub struct Config {
-    pub rpc_ws_url: String,
-    pub rpc_http_url: String,
-    pub mango_program_id: String,
-    pub mango_group_id: String,
-    pub mango_cache_id: String,
-    pub mango_signer_id: String,
-    pub serum_program_id: String,
-    pub snapshot_interval_secs: u64,
-    pub websocket_server_bind_address: String,
-    // how many getMultipleAccounts requests to send in parallel
-    pub parallel_rpc_requests: usize,
-    // typically 100 is the max number for getMultipleAccounts
-    pub get_multiple_accounts_count: usize,
-    pub early_candidate_percentage: f64,
-}
-
-pub fn encode_address(addr: &Pubkey) -> String {
-    bs58::encode(&addr.to_bytes()).into_string()
-}
-
-fn is_mango_account<'a>(
-    account: &'a AccountSharedData,
-    program_id: &Pubkey,
-    group_id: &Pubkey,
-) -> Option<&'a MangoAccount> {
-    let data = account.data();
-    if account.owner() != program_id || data.len() == 0 {
-        return None;
-    }
-    let kind = DataType::try_from(data[0]).ok()?;
-    if !matches!(kind, DataType::MangoAccount) {
-        return None;
-    }
-    if data.len() != std::mem::size_of::<MangoAccount>() {
-        return None;
-    }
-    let mango_account = MangoAccount::load_from_bytes(&data).expect("always Ok");
-    if mango_account.mango_group != *group_id {
-        return None;
-    }
-    Some(mango_account)
-}
-
-fn is_mango_cache<'a>(account: &'a AccountSharedData, program_id: &Pubkey) -> bool {
-    let data = account.data();
-    if account.owner() != program_id || data.len() == 0 {
-        return false;
+/// Update the price staleness metrics
+///
+/// Called on cache writes and on the full health check timer, so the metrics
+/// keep growing when the cache stops updating.
+fn update_price_staleness(
+    health_source: &dyn health_source::HealthSource,
+    chain_data: &ChainData,
+    metric_oracle_max_age: &mut metrics::MetricU64,
+    metric_oracle_stale_count: &mut metrics::MetricU64,
+) {
+    match health_source.price_staleness(chain_data) {
+        Ok(staleness) => {
+            metric_oracle_max_age.set(staleness.max_age_secs);
+            metric_oracle_stale_count.set(staleness.stale_count);
+        }
+        Err(err) => warn!("could not check price staleness: {:?}", err),
     }
-    let kind = DataType::try_from(data[0]).unwrap();
-    matches!(kind, DataType::MangoCache)
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        println!("requires a config file argument");
-        return Ok(());
-    }
+    let cli = cli::Cli::parse();
+    let mut config = cli.load_config()?;
 
-    let config: Config = {
-        let mut file = File::open(&args[1])?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        toml::from_str(&contents).unwrap()
-    };
+    solana_logger::setup_with_default(&config.log_level);
+    info!("startup");
 
-    let mango_program_id = Pubkey::from_str(&config.mango_program_id)?;
-    let mango_group_id = Pubkey::from_str(&config.mango_group_id)?;
-    let mango_cache_id = Pubkey::from_str(&config.mango_cache_id)?;
+    if config.health_check_threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.health_check_threads)
+            .build_global()?;
+    }
 
-    solana_logger::setup_with_default("info");
-    info!("startup");
+    let mut health_source = health_source::from_config(&config)?;
+
+    // Some settings can be reloaded at runtime, tasks get notified through this
+    let (config_sender, config_receiver) = watch::channel(config.clone());
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
 
     let metrics = metrics::start();
 
+    if cli.once {
+        return once::run(
+            &config,
+            &metrics,
+            health_source.as_ref(),
+            cli.snapshot_file.as_deref(),
+        )
+        .await;
+    }
+
     // Information about potentially liquidatable accounts is sent through this
     // channel and then forwarded to all connected websocket clients
-    let liquidation_candidate_sender = websocket_sink::start(config.clone()).await?;
+    //
+    // Clients are told when the service is ready: after the first snapshot
+    // and full health check.
+    let (ready_sender, ready_receiver) = watch::channel(false);
+    let (liquidation_candidate_sender, mut websocket_clients) =
+        websocket_sink::start(config.clone(), metrics.clone(), ready_receiver.clone()).await?;
+
+    // Writing liquidation events to a database, if configured
+    let persistence_job =
+        persistence::start(&config, &metrics, liquidation_candidate_sender.subscribe()).await?;
 
-    // Sourcing account and slot data from solana via websockets
     let (websocket_sender, websocket_receiver) =
         async_channel::unbounded::<websocket_source::Message>();
-    websocket_source::start(config.clone(), websocket_sender);
-
-    // Wait for some websocket data to accumulate before requesting snapshots,
-    // to make it more likely that there's no gap between the slot the snapshot
-    // was for and the slot of the first websocket messages.
-    tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
-
-    // Getting solana account snapshots via jsonrpc
     let (snapshot_sender, snapshot_receiver) =
         async_channel::unbounded::<snapshot_source::AccountSnapshot>();
-    snapshot_source::start(config.clone(), snapshot_sender);
+    let (websocket_connected_sender, websocket_connected_receiver) = watch::channel(false);
+
+    let mut metric_newest_processed_slot =
+        metrics.register_u64("chain_data_newest_processed_slot".into());
+    let mut metric_newest_processed_slot_time =
+        metrics.register_u64("chain_data_newest_processed_slot_unix_time".into());
+    let mut metric_last_snapshot_time = metrics.register_u64("last_snapshot_unix_time".into());
+    let mut metric_mango_accounts = metrics.register_u64("mango_accouns".into());
+
+    // Serving /healthz and /status, if configured
+    status_server::start(
+        config_receiver.clone(),
+        status_server::Inputs {
+            websocket_connected: websocket_connected_receiver.clone(),
+            ready: ready_receiver,
+            websocket_clients: websocket_clients.clone(),
+            newest_processed_slot: metric_newest_processed_slot.clone(),
+            newest_processed_slot_time: metric_newest_processed_slot_time.clone(),
+            last_snapshot_time: metric_last_snapshot_time.clone(),
+            tracked_accounts: metric_mango_accounts.clone(),
+        },
+    )?;
+    let source_jobs = if let Some(replay_path) = &cli.replay {
+        // Sourcing account and slot data from a recording
+        vec![recording::start_replay(
+            replay_path,
+            cli.replay_speed,
+            websocket_sender,
+            snapshot_sender,
+        )?]
+    } else {
+        // Sourcing account and slot data from solana via websockets
+        let websocket_source_job =
+            websocket_source::start(config.clone(), websocket_sender, websocket_connected_sender);
+
+        // Getting solana account snapshots via jsonrpc, once the websocket is connected
+        let snapshot_source_job = snapshot_source::start(
+            config_receiver,
+            websocket_connected_receiver,
+            health_source.clone(),
+            snapshot_sender,
+        );
+
+        vec![websocket_source_job, snapshot_source_job]
+    };
+
+    let recorder = match &config.record_path {
+        Some(path) => Some(recording::Recorder::create(path)?),
+        None => None,
+    };
 
     // The representation of current on-chain account data
     let mut chain_data = ChainData::new(&metrics);
@@ -140,102 +147,307 @@ async fn main() -> anyhow::Result<()> {
     // Needed to check health of them all when the cache updates.
     let mut mango_accounts = HashSet::<Pubkey>::new();
 
+    // The prices each MangoAccount depends on, to only check the affected accounts
+    // when prices change.
+    let mut price_dependencies = PriceDependencies::default();
+
+    // Global accounts like the MangoCache, as of the last time they were processed.
+    // Needed to find out which prices changed.
+    let mut global_accounts = HashMap::<Pubkey, AccountSharedData>::new();
+
     // List of accounts that are potentially liquidatable.
     //
     // Used to send a different message for newly liqudatable accounts and
     // accounts that are still liquidatable but not fresh anymore.
     //
     // This should actually be done per connected websocket client, and not globally.
-    let mut current_candidates = HashSet::<Pubkey>::new();
+    let mut current_candidates = healthcheck::Candidates::new(&metrics);
+
+    // Checks of many accounts at once, like after a snapshot, run in the background
+    // on a copy of `chain_data`. Single accounts are checked right away.
+    let mut background_checks = healthcheck::BackgroundChecks::default();
 
     // Is the first snapshot done? Only start checking account health when it is.
     let mut one_snapshot_done = false;
 
+    // Has the health of all accounts been checked after the first snapshot?
+    let mut ready = false;
+
+    // Sources only stop sending when replaying a recording
+    let mut websocket_source_done = false;
+    let mut snapshot_source_done = false;
+
     let mut metric_websocket_queue_len = metrics.register_u64("websocket_queue_length".into());
     let mut metric_snapshot_queue_len = metrics.register_u64("snapshot_queue_length".into());
-    let mut metric_mango_accounts = metrics.register_u64("mango_accouns".into());
+    let mut metric_oracle_max_age = metrics.register_u64("oracle_max_age_secs".into());
+    let mut metric_oracle_stale_count = metrics.register_u64("oracle_stale_count".into());
+    let mut metric_health_check_wall_time =
+        metrics.register_u64("health_check_wall_time_us".into());
+    let mut metric_partial_health_check_wall_time =
+        metrics.register_u64("partial_health_check_wall_time_us".into());
+
+    // Check all accounts regularly, even if the cache doesn't update
+    let mut full_health_check_interval = tokio::time::interval(tokio::time::Duration::from_secs(
+        config.full_health_check_interval_secs,
+    ));
 
     info!("main loop");
     loop {
         tokio::select! {
-            message = websocket_receiver.recv() => {
+            message = websocket_receiver.recv(), if !websocket_source_done => {
                 metric_websocket_queue_len.set(websocket_receiver.len() as u64);
-                let message = message.expect("channel not closed");
+                let message = match message {
+                    Ok(message) => message,
+                    Err(_) => {
+                        websocket_source_done = true;
+                        if snapshot_source_done {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record_websocket(&message);
+                }
 
                 // build a model of slots and accounts in `chain_data`
                 // this code should be generic so it can be reused in future projects
                 chain_data.update_from_websocket(message.clone());
+                if chain_data.newest_processed_slot() > metric_newest_processed_slot.value() {
+                    metric_newest_processed_slot.set(chain_data.newest_processed_slot());
+                    metric_newest_processed_slot_time.set(now_unix_timestamp());
+                }
 
                 // specific program logic using the mirrored data
                 match message {
                     websocket_source::Message::Account(account_write) => {
-                        if let Some(_mango_account) = is_mango_account(&account_write.account, &mango_program_id, &mango_group_id) {
+                        let kind = health_source.is_interesting_account(&account_write.pubkey, &account_write.account);
+                        if kind == Some(AccountKind::Health) {
                             // Track all MangoAccounts: we need to iterate over them later
                             mango_accounts.insert(account_write.pubkey);
                             metric_mango_accounts.set(mango_accounts.len() as u64);
+                            price_dependencies.update(
+                                account_write.pubkey,
+                                health_source.price_dependencies(&chain_data, &account_write.pubkey),
+                            );
 
                             if !one_snapshot_done {
                                 continue;
                             }
-                            if let Err(err) = healthcheck::process_accounts(
-                                    &config,
-                                    &chain_data,
-                                    &mango_group_id,
-                                    &mango_cache_id,
-                                    std::iter::once(&account_write.pubkey),
-                                    &mut current_candidates,
-                                    &liquidation_candidate_sender,
+                            healthcheck::process_accounts(
+                                &config,
+                                health_source.as_ref(),
+                                &chain_data,
+                                std::iter::once(&account_write.pubkey),
+                                &mut current_candidates,
+                                &liquidation_candidate_sender,
+                            );
+                        }
+
+                        if kind == Some(AccountKind::Global) {
+                            update_price_staleness(
+                                health_source.as_ref(),
+                                &chain_data,
+                                &mut metric_oracle_max_age,
+                                &mut metric_oracle_stale_count,
+                            );
+
+                            let changed_prices = match (
+                                global_accounts.get(&account_write.pubkey),
+                                chain_data.account(&account_write.pubkey),
                             ) {
-                                warn!("could not process account {}: {:?}", account_write.pubkey, err);
+                                (Some(old), Ok(new)) => health_source.changed_prices(&chain_data, old, new),
+                                _ => None,
+                            };
+                            if let Ok(new) = chain_data.account(&account_write.pubkey) {
+                                global_accounts.insert(account_write.pubkey, new.clone());
                             }
-                        }
 
-                        if account_write.pubkey == mango_cache_id && is_mango_cache(&account_write.account, &mango_program_id) {
                             if !one_snapshot_done {
                                 continue;
                             }
 
-                            // check health of all accounts
+                            // check health of the accounts that depend on the changed prices,
+                            // or all accounts if that's unknown
                             //
-                            // TODO: This could be done asynchronously by calling
-                            // let accounts = chain_data.accounts_snapshot();
-                            // and then working with the snapshot of the data
-                            //
-                            // However, this currently takes like 50ms for me in release builds,
-                            // so optimizing much seems unnecessary.
-                            if let Err(err) = healthcheck::process_accounts(
-                                    &config,
-                                    &chain_data,
-                                    &mango_group_id,
-                                    &mango_cache_id,
-                                    mango_accounts.iter(),
-                                    &mut current_candidates,
-                                    &liquidation_candidate_sender,
-                            ) {
-                                warn!("could not process accounts: {:?}", err);
+                            // Staleness can change without any price changing, that is picked
+                            // up by the full health check.
+                            match changed_prices {
+                                Some(changed_prices) => {
+                                    let affected = price_dependencies.affected(&changed_prices);
+                                    debug!(
+                                        "{} prices changed, checking {} accounts",
+                                        changed_prices.len(),
+                                        affected.len()
+                                    );
+                                    background_checks.request(affected.into_iter());
+                                }
+                                None => background_checks.request_full(),
                             }
+                            background_checks.start_pending(&health_source, &chain_data, &mango_accounts);
                         }
                     }
                     _ => {}
                 }
             },
-            message = snapshot_receiver.recv() => {
+            message = snapshot_receiver.recv(), if !snapshot_source_done => {
                 metric_snapshot_queue_len.set(snapshot_receiver.len() as u64);
-                let message = message.expect("channel not closed");
+                let message = match message {
+                    Ok(message) => message,
+                    Err(_) => {
+                        snapshot_source_done = true;
+                        if websocket_source_done {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record_snapshot(&message);
+                }
 
                 // Track all mango account pubkeys
+                let mut snapshot_health_accounts = vec![];
+                let mut snapshot_global_accounts = vec![];
                 for update in message.accounts.iter() {
-                    if let Some(_mango_account) = is_mango_account(&update.account, &mango_program_id, &mango_group_id) {
-                        mango_accounts.insert(update.pubkey);
+                    match health_source.is_interesting_account(&update.pubkey, &update.account) {
+                        Some(AccountKind::Health) => {
+                            mango_accounts.insert(update.pubkey);
+                            snapshot_health_accounts.push(update.pubkey);
+                        }
+                        Some(AccountKind::Global) => snapshot_global_accounts.push(update.pubkey),
+                        None => {}
                     }
                 }
                 metric_mango_accounts.set(mango_accounts.len() as u64);
 
                 chain_data.update_from_snapshot(message);
                 one_snapshot_done = true;
+                metric_last_snapshot_time.set(now_unix_timestamp());
 
-                // TODO: trigger a full health check
+                for pubkey in snapshot_health_accounts {
+                    price_dependencies.update(pubkey, health_source.price_dependencies(&chain_data, &pubkey));
+                }
+                for pubkey in snapshot_global_accounts {
+                    if let Ok(account) = chain_data.account(&pubkey) {
+                        global_accounts.insert(pubkey, account.clone());
+                    }
+                }
+
+                background_checks.request_full();
+                background_checks.start_pending(&health_source, &chain_data, &mango_accounts);
             },
+            _ = full_health_check_interval.tick() => {
+                if !one_snapshot_done {
+                    continue;
+                }
+                update_price_staleness(
+                    health_source.as_ref(),
+                    &chain_data,
+                    &mut metric_oracle_max_age,
+                    &mut metric_oracle_stale_count,
+                );
+                background_checks.request_full();
+                background_checks.start_pending(&health_source, &chain_data, &mango_accounts);
+            },
+            result = background_checks.finished() => {
+                match result {
+                    Ok(result) => {
+                        healthcheck::process_healths(
+                            &config,
+                            health_source.as_ref(),
+                            &result.chain_data,
+                            &result.healths,
+                            &mut current_candidates,
+                            &liquidation_candidate_sender,
+                        );
+                        let wall_time = result.wall_time.as_micros() as u64;
+                        if result.full {
+                            metric_health_check_wall_time.set(wall_time);
+                            if !ready {
+                                info!("first full health check done, ready");
+                                ready = true;
+                                let _ = ready_sender.send(true);
+                            }
+                        } else {
+                            metric_partial_health_check_wall_time.set(wall_time);
+                        }
+                    }
+                    Err(err) => warn!("could not check account health: {:?}", err),
+                }
+                background_checks.start_pending(&health_source, &chain_data, &mango_accounts);
+            },
+            _ = sighup.recv() => {
+                info!("reloading config");
+                let new_config = match cli.load_config() {
+                    Ok(c) => c,
+                    Err(err) => {
+                        warn!("could not reload config: {:?}", err);
+                        continue;
+                    }
+                };
+                let reloaded = config.with_reloadable_settings(&new_config);
+                if reloaded != new_config {
+                    warn!("some changed settings only take effect after a restart");
+                }
+                match health_source::from_config(&reloaded) {
+                    Ok(hs) => health_source = hs,
+                    Err(err) => {
+                        warn!("could not apply reloaded config: {:?}", err);
+                        continue;
+                    }
+                }
+                if reloaded.log_level != config.log_level {
+                    solana_logger::setup_with(&reloaded.log_level);
+                }
+                if reloaded.full_health_check_interval_secs != config.full_health_check_interval_secs {
+                    full_health_check_interval = tokio::time::interval(
+                        tokio::time::Duration::from_secs(reloaded.full_health_check_interval_secs),
+                    );
+                }
+                config = reloaded;
+                let _ = config_sender.send(config.clone());
+                info!("config reloaded");
+            },
+            _ = sigterm.recv() => {
+                info!("received SIGTERM");
+                break;
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("received SIGINT");
+                break;
+            },
+        }
+    }
+
+    info!("shutting down");
+    for job in source_jobs {
+        job.abort();
+    }
+    if let Some(recorder) = recorder {
+        recorder.finish();
+    }
+
+    // Clients receive the shutdown message after all previously sent messages,
+    // so waiting for them to disconnect also flushes pending messages.
+    let _ = liquidation_candidate_sender.send(websocket_sink::LiquidationCanditate::Shutdown);
+    let deadline = tokio::time::Instant::now()
+        + tokio::time::Duration::from_secs(config.shutdown_grace_period_secs);
+    if tokio::time::timeout_at(deadline, websocket_clients.drained())
+        .await
+        .is_err()
+    {
+        warn!(
+            "{} websocket clients still connected after the grace period",
+            websocket_clients.get()
+        );
+    }
+    if let Some(job) = persistence_job {
+        if tokio::time::timeout_at(deadline, job).await.is_err() {
+            warn!("liquidation events still being written after the grace period");
         }
     }
+
+    info!("shutdown complete");
+    Ok(())
 }
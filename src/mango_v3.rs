@@ -0,0 +1,180 @@
+use {
+    crate::chain_data::ChainData,
+    crate::health::{self, Health, LiquidationSuggestion, StalenessLimits, TokenBalance},
+    crate::health_source::{AccountKind, HealthSource, PriceStaleness},
+    anyhow::Context,
+    fixed::types::I80F48,
+    mango::state::{DataType, MangoAccount, MangoCache, MangoGroup},
+    mango_common::Loadable,
+    solana_sdk::account::{AccountSharedData, ReadableAccount},
+    solana_sdk::pubkey::Pubkey,
+    std::collections::HashMap,
+    std::str::FromStr,
+};
+
+fn is_mango_account<'a>(
+    account: &'a AccountSharedData,
+    program_id: &Pubkey,
+    group_id: &Pubkey,
+) -> Option<&'a MangoAccount> {
+    let data = account.data();
+    if account.owner() != program_id || data.len() == 0 {
+        return None;
+    }
+    let kind = DataType::try_from(data[0]).ok()?;
+    if !matches!(kind, DataType::MangoAccount) {
+        return None;
+    }
+    if data.len() != std::mem::size_of::<MangoAccount>() {
+        return None;
+    }
+    let mango_account = MangoAccount::load_from_bytes(&data).expect("always Ok");
+    if mango_account.mango_group != *group_id {
+        return None;
+    }
+    Some(mango_account)
+}
+
+fn is_mango_cache<'a>(account: &'a AccountSharedData, program_id: &Pubkey) -> bool {
+    let data = account.data();
+    if account.owner() != program_id || data.len() == 0 {
+        return false;
+    }
+    let kind = DataType::try_from(data[0]).unwrap();
+    matches!(kind, DataType::MangoCache)
+}
+
+/// Health computation for the mango v3 program
+pub struct MangoV3 {
+    program_id: Pubkey,
+    group_id: Pubkey,
+    cache_id: Pubkey,
+    staleness_limits: StalenessLimits,
+}
+
+impl MangoV3 {
+    pub fn new(config: &crate::Config) -> anyhow::Result<Self> {
+        let oracle_max_age = config
+            .oracle_max_staleness_overrides
+            .iter()
+            .map(|(oracle, max_age)| Ok((Pubkey::from_str(oracle)?, *max_age)))
+            .collect::<anyhow::Result<HashMap<Pubkey, u64>>>()
+            .context("parsing oracle_max_staleness_overrides")?;
+        Ok(Self {
+            program_id: Pubkey::from_str(&config.mango_program_id)?,
+            group_id: Pubkey::from_str(&config.mango_group_id)?,
+            cache_id: Pubkey::from_str(&config.mango_cache_id)?,
+            staleness_limits: StalenessLimits {
+                default_max_age: config.oracle_max_staleness_secs,
+                oracle_max_age,
+            },
+        })
+    }
+}
+
+impl HealthSource for MangoV3 {
+    fn is_interesting_account(
+        &self,
+        pubkey: &Pubkey,
+        account: &AccountSharedData,
+    ) -> Option<AccountKind> {
+        if is_mango_account(account, &self.program_id, &self.group_id).is_some() {
+            return Some(AccountKind::Health);
+        }
+        if *pubkey == self.cache_id && is_mango_cache(account, &self.program_id) {
+            return Some(AccountKind::Global);
+        }
+        None
+    }
+
+    fn tracked_keys(&self, account: &AccountSharedData) -> Vec<Pubkey> {
+        match is_mango_account(account, &self.program_id, &self.group_id) {
+            Some(mango_account) => mango_account
+                .in_margin_basket
+                .iter()
+                .zip(mango_account.spot_open_orders.iter())
+                .filter_map(|(in_basket, oo)| in_basket.then(|| *oo))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn compute_health(&self, chain_data: &ChainData, pubkey: &Pubkey) -> anyhow::Result<Health> {
+        health::compute_health(
+            chain_data,
+            &self.group_id,
+            &self.cache_id,
+            pubkey,
+            &self.staleness_limits,
+        )
+    }
+
+    fn liquidation(
+        &self,
+        chain_data: &ChainData,
+        pubkey: &Pubkey,
+        health: &Health,
+    ) -> anyhow::Result<Option<(LiquidationSuggestion, I80F48)>> {
+        health::suggest_liquidation(chain_data, &self.group_id, &self.cache_id, pubkey, health)
+    }
+
+    fn balances(
+        &self,
+        chain_data: &ChainData,
+        pubkey: &Pubkey,
+    ) -> anyhow::Result<Vec<TokenBalance>> {
+        health::token_balances(chain_data, &self.group_id, &self.cache_id, pubkey)
+    }
+
+    fn price_staleness(&self, chain_data: &ChainData) -> anyhow::Result<PriceStaleness> {
+        let (group, cache) =
+            health::load_group_and_cache(chain_data, &self.group_id, &self.cache_id)?;
+        let now = crate::now_unix_timestamp();
+        let limits = &self.staleness_limits;
+        let mut staleness = PriceStaleness::default();
+        for i in 0..group.num_oracles {
+            if group.oracles[i] == Pubkey::default() {
+                continue;
+            }
+            let last_update = cache.price_cache[i].last_update;
+            staleness.max_age_secs = staleness.max_age_secs.max(now.saturating_sub(last_update));
+            if limits.is_stale(limits.max_age(&group.oracles[i]), last_update, now) {
+                staleness.stale_count += 1;
+            }
+        }
+        Ok(staleness)
+    }
+
+    fn price_dependencies(&self, chain_data: &ChainData, pubkey: &Pubkey) -> Option<Vec<usize>> {
+        let group = health::load_mango_account_from_chain::<MangoGroup>(
+            DataType::MangoGroup,
+            chain_data,
+            &self.group_id,
+        )
+        .ok()?;
+        let account = health::load_mango_account_from_chain::<MangoAccount>(
+            DataType::MangoAccount,
+            chain_data,
+            pubkey,
+        )
+        .ok()?;
+        Some(health::price_dependencies(group, account))
+    }
+
+    fn changed_prices(
+        &self,
+        chain_data: &ChainData,
+        old: &AccountSharedData,
+        new: &AccountSharedData,
+    ) -> Option<Vec<usize>> {
+        let group = health::load_mango_account_from_chain::<MangoGroup>(
+            DataType::MangoGroup,
+            chain_data,
+            &self.group_id,
+        )
+        .ok()?;
+        let old = health::load_mango_account::<MangoCache>(DataType::MangoCache, old).ok()?;
+        let new = health::load_mango_account::<MangoCache>(DataType::MangoCache, new).ok()?;
+        Some(health::changed_cache_entries(group, old, new))
+    }
+}
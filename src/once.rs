@@ -0,0 +1,76 @@
+use {
+    crate::{
+        chain_data::ChainData,
+        health_source::{AccountKind, HealthSource},
+        healthcheck, metrics, recording, snapshot_source,
+        websocket_sink::{JsonRpcLiquidatablePayload, LiquidationCanditate},
+        Config,
+    },
+    log::*,
+    solana_sdk::pubkey::Pubkey,
+    std::collections::HashSet,
+    tokio::sync::broadcast,
+};
+
+/// Check the health of all accounts in a single snapshot and print the
+/// candidates as JSON to stdout
+///
+/// The snapshot is requested from the RPC node, or taken from a recording.
+pub async fn run(
+    config: &Config,
+    metrics: &metrics::Metrics,
+    health_source: &dyn HealthSource,
+    recording_path: Option<&str>,
+) -> anyhow::Result<()> {
+    let snapshot = match recording_path {
+        Some(path) => recording::load_last_snapshot(path)?,
+        None => snapshot_source::get_snapshot(config, health_source).await?,
+    };
+    let newest_slot = snapshot.accounts.iter().map(|a| a.slot).max().unwrap_or(0);
+
+    let mango_accounts = snapshot
+        .accounts
+        .iter()
+        .filter(|update| {
+            health_source.is_interesting_account(&update.pubkey, &update.account)
+                == Some(AccountKind::Health)
+        })
+        .map(|update| update.pubkey)
+        .collect::<HashSet<Pubkey>>();
+    info!(
+        "checking {} accounts at slot {}",
+        mango_accounts.len(),
+        newest_slot
+    );
+
+    let mut chain_data = ChainData::new(metrics);
+    chain_data.update_from_snapshot(snapshot);
+    // without slot updates, nothing would be considered live
+    chain_data.mark_rooted(newest_slot);
+
+    // every candidate produces a Start and a Now or Bankruptcy message
+    let (tx, mut rx) = broadcast::channel(2 * mango_accounts.len() + 1);
+    let mut current_candidates = healthcheck::Candidates::new(metrics);
+    healthcheck::process_accounts(
+        config,
+        health_source,
+        &chain_data,
+        mango_accounts.iter(),
+        &mut current_candidates,
+        &tx,
+    );
+
+    // Start messages are sent in order, the most urgent first
+    let mut candidates = vec![];
+    while let Ok(message) = rx.try_recv() {
+        if let LiquidationCanditate::Start { info } = message {
+            candidates.push(info);
+        }
+    }
+    let payloads = candidates
+        .iter()
+        .map(JsonRpcLiquidatablePayload::from)
+        .collect::<Vec<_>>();
+    println!("{}", serde_json::to_string_pretty(&payloads)?);
+    Ok(())
+}
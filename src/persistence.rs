@@ -0,0 +1,360 @@
+use {
+    crate::{
+        metrics,
+        websocket_sink::{HealthInfo, LiquidationCanditate},
+        Config,
+    },
+    log::*,
+    serde_derive::Serialize,
+    std::time::{Duration, SystemTime, UNIX_EPOCH},
+    tokio::sync::{broadcast, mpsc},
+    tokio::task::JoinHandle,
+};
+
+// Events are written when this many have accumulated, or after FLUSH_INTERVAL
+const BATCH_SIZE: usize = 100;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// balances is a JSON array of BalanceRow
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS liquidation_events (
+    account TEXT NOT NULL,
+    event TEXT NOT NULL,
+    slot BIGINT NOT NULL,
+    timestamp_ms BIGINT NOT NULL,
+    being_liquidated BOOLEAN NOT NULL,
+    health_fraction DOUBLE PRECISION NOT NULL,
+    assets DOUBLE PRECISION NOT NULL,
+    liabilities DOUBLE PRECISION NOT NULL,
+    balances TEXT NOT NULL
+)";
+
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+const INSERT: &str = "INSERT INTO liquidation_events
+    (account, event, slot, timestamp_ms, being_liquidated, health_fraction, assets, liabilities, balances)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)";
+
+#[derive(Serialize)]
+struct BalanceRow {
+    token_index: usize,
+    mint: String,
+    deposit: f64, // native
+    borrow: f64,  // native
+}
+
+#[derive(Clone, Debug)]
+struct EventRow {
+    account: String,
+    event: &'static str,
+    slot: i64,
+    timestamp_ms: i64,
+    being_liquidated: bool,
+    health_fraction: f64,
+    assets: f64,
+    liabilities: f64,
+    balances: String,
+}
+
+impl EventRow {
+    fn new(event: &'static str, info: &HealthInfo) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        let balances = info
+            .balances
+            .iter()
+            .map(|balance| BalanceRow {
+                token_index: balance.token_index,
+                mint: balance.mint.to_string(),
+                deposit: balance.deposit.to_num::<f64>(),
+                borrow: balance.borrow.to_num::<f64>(),
+            })
+            .collect::<Vec<_>>();
+        Self {
+            account: info.account.to_string(),
+            event,
+            slot: info.slot as i64,
+            timestamp_ms,
+            being_liquidated: info.being_liquidated,
+            health_fraction: info.health_fraction.to_num::<f64>(),
+            assets: info.assets.to_num::<f64>(),
+            liabilities: info.liabilities.to_num::<f64>(),
+            balances: serde_json::to_string(&balances).unwrap(),
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use {
+        super::{EventRow, CREATE_TABLE, INSERT},
+        log::*,
+        std::time::Duration,
+    };
+
+    const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    pub struct Postgres {
+        connection_string: String,
+        client: tokio_postgres::Client,
+    }
+
+    async fn connect(connection_string: &str) -> anyhow::Result<tokio_postgres::Client> {
+        let (client, connection) =
+            tokio_postgres::connect(connection_string, tokio_postgres::NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                error!("postgres connection error: {:?}", err);
+            }
+        });
+        client.execute(CREATE_TABLE, &[]).await?;
+        Ok(client)
+    }
+
+    impl Postgres {
+        pub async fn connect(connection_string: &str) -> anyhow::Result<Self> {
+            Ok(Self {
+                connection_string: connection_string.to_string(),
+                client: connect(connection_string).await?,
+            })
+        }
+
+        /// Replace a closed connection, retrying with exponential backoff until it works
+        async fn reconnect(&mut self) {
+            let mut backoff = RECONNECT_MIN_BACKOFF;
+            loop {
+                match connect(&self.connection_string).await {
+                    Ok(client) => {
+                        info!("reconnected to postgres");
+                        self.client = client;
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "could not reconnect to postgres, retrying in {:?}: {:?}",
+                            backoff, err
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    }
+                }
+            }
+        }
+
+        async fn try_insert(&mut self, rows: &[EventRow]) -> anyhow::Result<()> {
+            let transaction = self.client.transaction().await?;
+            let statement = transaction.prepare(INSERT).await?;
+            for row in rows.iter() {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &row.account,
+                            &row.event,
+                            &row.slot,
+                            &row.timestamp_ms,
+                            &row.being_liquidated,
+                            &row.health_fraction,
+                            &row.assets,
+                            &row.liabilities,
+                            &row.balances,
+                        ],
+                    )
+                    .await?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        }
+
+        /// Rows that couldn't be written because the connection was lost are
+        /// written again after reconnecting
+        pub async fn insert(&mut self, rows: &[EventRow]) -> anyhow::Result<()> {
+            loop {
+                match self.try_insert(rows).await {
+                    Err(err) if self.client.is_closed() => {
+                        warn!("lost the postgres connection: {:?}", err);
+                        self.reconnect().await;
+                    }
+                    result => return result,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use {
+        super::{EventRow, CREATE_TABLE, INSERT},
+        std::sync::{Arc, Mutex},
+    };
+
+    // rusqlite is blocking, it's used from spawn_blocking
+    pub struct Sqlite {
+        connection: Arc<Mutex<rusqlite::Connection>>,
+    }
+
+    impl Sqlite {
+        pub fn open(path: &str) -> anyhow::Result<Self> {
+            let connection = rusqlite::Connection::open(path)?;
+            connection.execute(CREATE_TABLE, [])?;
+            Ok(Self {
+                connection: Arc::new(Mutex::new(connection)),
+            })
+        }
+
+        pub async fn insert(&mut self, rows: Vec<EventRow>) -> anyhow::Result<()> {
+            let connection = self.connection.clone();
+            tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+                let mut connection = connection.lock().unwrap();
+                let transaction = connection.transaction()?;
+                {
+                    let mut statement = transaction.prepare_cached(INSERT)?;
+                    for row in rows.iter() {
+                        statement.execute(rusqlite::params![
+                            row.account,
+                            row.event,
+                            row.slot,
+                            row.timestamp_ms,
+                            row.being_liquidated,
+                            row.health_fraction,
+                            row.assets,
+                            row.liabilities,
+                            row.balances,
+                        ])?;
+                    }
+                }
+                transaction.commit()?;
+                Ok(())
+            })
+            .await?
+        }
+    }
+}
+
+// Without any of the database features this has no variants, and
+// persistence can't be configured.
+enum Database {
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Postgres),
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::Sqlite),
+}
+
+impl Database {
+    async fn connect(config: &Config) -> anyhow::Result<Option<Self>> {
+        match (&config.postgres_connection_string, &config.sqlite_path) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of postgres_connection_string and sqlite_path can be set")
+            }
+            #[cfg(feature = "postgres")]
+            (Some(connection_string), None) => Ok(Some(Database::Postgres(
+                postgres::Postgres::connect(connection_string).await?,
+            ))),
+            #[cfg(not(feature = "postgres"))]
+            (Some(_), None) => {
+                anyhow::bail!(
+                    "postgres_connection_string is set, but the postgres feature is not enabled"
+                )
+            }
+            #[cfg(feature = "sqlite")]
+            (None, Some(path)) => Ok(Some(Database::Sqlite(sqlite::Sqlite::open(path)?))),
+            #[cfg(not(feature = "sqlite"))]
+            (None, Some(_)) => {
+                anyhow::bail!("sqlite_path is set, but the sqlite feature is not enabled")
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    async fn insert(&mut self, rows: Vec<EventRow>) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "postgres")]
+            Database::Postgres(postgres) => postgres.insert(&rows).await,
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(sqlite) => sqlite.insert(rows).await,
+            #[cfg(not(any(feature = "postgres", feature = "sqlite")))]
+            _ => unreachable!("no database to write {} rows to", rows.len()),
+        }
+    }
+}
+
+/// Write all candidate events to the configured database, if any
+///
+/// Events are collected from their own broadcast receiver and written in batches
+/// by a separate task, so that slow writes don't make the receiver lag. The returned
+/// handle finishes once all events up to the Shutdown message are written.
+pub async fn start(
+    config: &Config,
+    metrics: &metrics::Metrics,
+    mut rx: broadcast::Receiver<LiquidationCanditate>,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let mut database = match Database::connect(config).await? {
+        Some(database) => database,
+        None => return Ok(None),
+    };
+    info!("persisting liquidation events");
+
+    let mut metric_written = metrics.register_u64("persistence_written_events".into());
+    let mut metric_dropped = metrics.register_u64("persistence_dropped_events".into());
+    let mut metric_dropped_c = metric_dropped.clone();
+
+    let (batch_sender, mut batch_receiver) = mpsc::unbounded_channel::<Vec<EventRow>>();
+
+    tokio::spawn(async move {
+        let mut batch = vec![];
+        let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                event = rx.recv() => {
+                    match event {
+                        Ok(LiquidationCanditate::Start { info }) => {
+                            batch.push(EventRow::new("start", &info))
+                        }
+                        Ok(LiquidationCanditate::Now { info }) => {
+                            batch.push(EventRow::new("warning", &info))
+                        }
+                        Ok(LiquidationCanditate::Bankruptcy { info }) => {
+                            batch.push(EventRow::new("bankruptcy", &info))
+                        }
+                        Ok(LiquidationCanditate::Stop { info }) => {
+                            batch.push(EventRow::new("stop", &info))
+                        }
+                        Ok(LiquidationCanditate::Shutdown)
+                        | Err(broadcast::error::RecvError::Closed) => break,
+                        Err(broadcast::error::RecvError::Lagged(count)) => {
+                            warn!("persistence lagged, {} messages lost", count);
+                            metric_dropped_c.add(count);
+                        }
+                    }
+                    if batch.len() >= BATCH_SIZE {
+                        let _ = batch_sender.send(std::mem::take(&mut batch));
+                    }
+                },
+                _ = flush_interval.tick() => {
+                    if !batch.is_empty() {
+                        let _ = batch_sender.send(std::mem::take(&mut batch));
+                    }
+                },
+            }
+        }
+        if !batch.is_empty() {
+            let _ = batch_sender.send(batch);
+        }
+    });
+
+    Ok(Some(tokio::spawn(async move {
+        while let Some(rows) = batch_receiver.recv().await {
+            let count = rows.len() as u64;
+            match database.insert(rows).await {
+                Ok(()) => metric_written.add(count),
+                Err(err) => {
+                    warn!("could not write {} liquidation events: {:?}", count, err);
+                    metric_dropped.add(count);
+                }
+            }
+        }
+    })))
+}
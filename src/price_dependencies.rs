@@ -0,0 +1,109 @@
+use {
+    solana_sdk::pubkey::Pubkey,
+    std::collections::{HashMap, HashSet},
+};
+
+/// Which accounts are exposed to which prices
+///
+/// Prices are identified by the token or market index the HealthSource uses.
+/// A cache update only needs to recompute the accounts exposed to the prices
+/// that changed.
+#[derive(Default)]
+pub struct PriceDependencies {
+    accounts_by_price: HashMap<usize, HashSet<Pubkey>>,
+    prices_by_account: HashMap<Pubkey, Vec<usize>>,
+    /// accounts whose dependencies are unknown, every change affects them
+    unknown: HashSet<Pubkey>,
+}
+
+impl PriceDependencies {
+    /// Replace the dependencies of `account`, None if they are unknown
+    pub fn update(&mut self, account: Pubkey, prices: Option<Vec<usize>>) {
+        if let Some(old_prices) = self.prices_by_account.remove(&account) {
+            for price in old_prices {
+                if let Some(accounts) = self.accounts_by_price.get_mut(&price) {
+                    accounts.remove(&account);
+                }
+            }
+        }
+        self.unknown.remove(&account);
+
+        match prices {
+            Some(prices) => {
+                for price in prices.iter() {
+                    self.accounts_by_price
+                        .entry(*price)
+                        .or_default()
+                        .insert(account);
+                }
+                self.prices_by_account.insert(account, prices);
+            }
+            None => {
+                self.unknown.insert(account);
+            }
+        }
+    }
+
+    /// Accounts whose health can be affected by a change of the `changed` prices
+    pub fn affected(&self, changed: &[usize]) -> HashSet<Pubkey> {
+        let mut accounts = self.unknown.clone();
+        for price in changed {
+            if let Some(price_accounts) = self.accounts_by_price.get(price) {
+                accounts.extend(price_accounts.iter());
+            }
+        }
+        accounts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(accounts: HashSet<Pubkey>) -> Vec<Pubkey> {
+        let mut accounts = accounts.into_iter().collect::<Vec<_>>();
+        accounts.sort();
+        accounts
+    }
+
+    #[test]
+    fn affected_by_changed_prices() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut deps = PriceDependencies::default();
+        deps.update(a, Some(vec![0, 1]));
+        deps.update(b, Some(vec![1, 2]));
+
+        assert_eq!(sorted(deps.affected(&[0])), vec![a]);
+        assert_eq!(sorted(deps.affected(&[1])), sorted([a, b].into()));
+        assert_eq!(sorted(deps.affected(&[2, 3])), vec![b]);
+        assert!(deps.affected(&[3]).is_empty());
+        assert!(deps.affected(&[]).is_empty());
+    }
+
+    #[test]
+    fn update_replaces_dependencies() {
+        let a = Pubkey::new_unique();
+        let mut deps = PriceDependencies::default();
+        deps.update(a, Some(vec![0]));
+        deps.update(a, Some(vec![1]));
+
+        assert!(deps.affected(&[0]).is_empty());
+        assert_eq!(sorted(deps.affected(&[1])), vec![a]);
+    }
+
+    #[test]
+    fn unknown_dependencies_are_always_affected() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut deps = PriceDependencies::default();
+        deps.update(a, None);
+        deps.update(b, Some(vec![0]));
+
+        assert_eq!(sorted(deps.affected(&[])), vec![a]);
+        assert_eq!(sorted(deps.affected(&[0])), sorted([a, b].into()));
+
+        // becoming known removes it from the unknown set
+        deps.update(a, Some(vec![1]));
+        assert!(deps.affected(&[]).is_empty());
+        assert_eq!(sorted(deps.affected(&[0])), vec![b]);
+    }
+}
@@ -0,0 +1,277 @@
+use {
+    crate::{snapshot_source, websocket_source},
+    log::*,
+    serde_derive::{Deserialize, Serialize},
+    solana_client::rpc_response::SlotUpdate,
+    solana_sdk::{account::AccountSharedData, pubkey::Pubkey},
+    std::fs::File,
+    std::io::{BufReader, BufWriter, Write},
+    std::sync::{mpsc, Arc},
+    std::time::Instant,
+    tokio::task::JoinHandle,
+};
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedAccount {
+    pubkey: Pubkey,
+    slot: u64,
+    account: AccountSharedData,
+}
+
+// SlotUpdate can't be stored directly: bincode doesn't support its serde
+// representation. Only the updates that ChainData uses are recorded.
+#[derive(Serialize, Deserialize)]
+enum RecordedSlot {
+    CreatedBank { slot: u64, parent: u64 },
+    OptimisticConfirmation { slot: u64 },
+    Root { slot: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Account(RecordedAccount),
+    Slot(RecordedSlot),
+    Snapshot(Vec<RecordedAccount>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// milliseconds since the recording started
+    elapsed_ms: u64,
+    entry: Entry,
+}
+
+impl RecordedAccount {
+    fn from_websocket(update: &websocket_source::AccountUpdate) -> Self {
+        Self {
+            pubkey: update.pubkey,
+            slot: update.slot,
+            account: update.account.clone(),
+        }
+    }
+
+    fn from_snapshot(update: &snapshot_source::AccountUpdate) -> Self {
+        Self {
+            pubkey: update.pubkey,
+            slot: update.slot,
+            account: update.account.clone(),
+        }
+    }
+}
+
+/// Append-only log of the websocket messages and snapshots the service received
+///
+/// Writing happens on a separate thread, recording never blocks the caller.
+pub struct Recorder {
+    sender: mpsc::Sender<Entry>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> anyhow::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(
+            &mut writer,
+            &Header {
+                version: FORMAT_VERSION,
+            },
+        )?;
+
+        let (sender, receiver) = mpsc::channel::<Entry>();
+        let start = Instant::now();
+        let thread = std::thread::spawn(move || {
+            for entry in receiver.iter() {
+                let record = Record {
+                    elapsed_ms: start.elapsed().as_millis() as u64,
+                    entry,
+                };
+                if let Err(err) = bincode::serialize_into(&mut writer, &record) {
+                    error!("could not write recording, stopping: {:?}", err);
+                    return;
+                }
+            }
+            if let Err(err) = writer.flush() {
+                error!("could not flush recording: {:?}", err);
+            }
+        });
+
+        info!("recording to {}", path);
+        Ok(Self { sender, thread })
+    }
+
+    pub fn record_websocket(&self, message: &websocket_source::Message) {
+        let entry = match message {
+            websocket_source::Message::Account(update) => {
+                Entry::Account(RecordedAccount::from_websocket(update))
+            }
+            websocket_source::Message::Slot(update) => match **update {
+                SlotUpdate::CreatedBank { slot, parent, .. } => {
+                    Entry::Slot(RecordedSlot::CreatedBank { slot, parent })
+                }
+                SlotUpdate::OptimisticConfirmation { slot, .. } => {
+                    Entry::Slot(RecordedSlot::OptimisticConfirmation { slot })
+                }
+                SlotUpdate::Root { slot, .. } => Entry::Slot(RecordedSlot::Root { slot }),
+                _ => return,
+            },
+        };
+        let _ = self.sender.send(entry);
+    }
+
+    pub fn record_snapshot(&self, snapshot: &snapshot_source::AccountSnapshot) {
+        let accounts = snapshot
+            .accounts
+            .iter()
+            .map(RecordedAccount::from_snapshot)
+            .collect();
+        let _ = self.sender.send(Entry::Snapshot(accounts));
+    }
+
+    /// Write out everything that was recorded so far and close the file
+    pub fn finish(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+fn is_end_of_file(err: &bincode::Error) -> bool {
+    match err.as_ref() {
+        bincode::ErrorKind::Io(io_err) => io_err.kind() == std::io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+fn open_recording(path: &str) -> anyhow::Result<BufReader<File>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header: Header = bincode::deserialize_from(&mut reader)?;
+    if header.version != FORMAT_VERSION {
+        anyhow::bail!(
+            "unsupported recording version {}, expected {}",
+            header.version,
+            FORMAT_VERSION
+        );
+    }
+    Ok(reader)
+}
+
+fn read_record(reader: &mut BufReader<File>) -> anyhow::Result<Option<Record>> {
+    match bincode::deserialize_from(reader) {
+        Ok(record) => Ok(Some(record)),
+        Err(err) if is_end_of_file(&err) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn to_snapshot(accounts: Vec<RecordedAccount>) -> snapshot_source::AccountSnapshot {
+    snapshot_source::AccountSnapshot {
+        accounts: accounts
+            .into_iter()
+            .map(|account| snapshot_source::AccountUpdate {
+                pubkey: account.pubkey,
+                slot: account.slot,
+                account: account.account,
+            })
+            .collect(),
+    }
+}
+
+/// The last snapshot in a recording
+pub fn load_last_snapshot(path: &str) -> anyhow::Result<snapshot_source::AccountSnapshot> {
+    let mut reader = open_recording(path)?;
+    let mut last_snapshot = None;
+    while let Some(record) = read_record(&mut reader)? {
+        if let Entry::Snapshot(accounts) = record.entry {
+            last_snapshot = Some(accounts);
+        }
+    }
+    last_snapshot
+        .map(to_snapshot)
+        .ok_or_else(|| anyhow::anyhow!("recording {} contains no snapshot", path))
+}
+
+/// Feed a recording into the channels the sources usually send to
+///
+/// With speed 1.0 the original timing is reproduced, higher ones are faster and
+/// 0 replays as fast as possible. The senders are dropped once the recording is done.
+pub fn start_replay(
+    path: &str,
+    speed: f64,
+    websocket_sender: async_channel::Sender<websocket_source::Message>,
+    snapshot_sender: async_channel::Sender<snapshot_source::AccountSnapshot>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let mut reader = open_recording(path)?;
+    info!("replaying {} at speed {}", path, speed);
+
+    Ok(tokio::spawn(async move {
+        let start = tokio::time::Instant::now();
+        let mut count = 0u64;
+        let mut last_was_snapshot = false;
+        loop {
+            // reading is cheap compared to everything that happens to the data later
+            let record = match read_record(&mut reader) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("could not read recording: {:?}", err);
+                    break;
+                }
+            };
+
+            if speed > 0.0 {
+                let offset =
+                    std::time::Duration::from_secs_f64(record.elapsed_ms as f64 / 1000.0 / speed);
+                tokio::time::sleep_until(start + offset).await;
+            }
+
+            // The main loop reads both channels in no particular order. To keep the
+            // recorded order, wait for one to drain before switching to the other.
+            let is_snapshot = matches!(record.entry, Entry::Snapshot(_));
+            if is_snapshot != last_was_snapshot {
+                while !websocket_sender.is_empty() || !snapshot_sender.is_empty() {
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+            }
+            last_was_snapshot = is_snapshot;
+
+            match record.entry {
+                Entry::Account(account) => {
+                    let message =
+                        websocket_source::Message::Account(websocket_source::AccountUpdate {
+                            pubkey: account.pubkey,
+                            slot: account.slot,
+                            account: account.account,
+                        });
+                    let _ = websocket_sender.send(message).await;
+                }
+                Entry::Slot(slot) => {
+                    let update = match slot {
+                        RecordedSlot::CreatedBank { slot, parent } => SlotUpdate::CreatedBank {
+                            slot,
+                            parent,
+                            timestamp: 0,
+                        },
+                        RecordedSlot::OptimisticConfirmation { slot } => {
+                            SlotUpdate::OptimisticConfirmation { slot, timestamp: 0 }
+                        }
+                        RecordedSlot::Root { slot } => SlotUpdate::Root { slot, timestamp: 0 },
+                    };
+                    let _ = websocket_sender
+                        .send(websocket_source::Message::Slot(Arc::new(update)))
+                        .await;
+                }
+                Entry::Snapshot(accounts) => {
+                    let _ = snapshot_sender.send(to_snapshot(accounts)).await;
+                }
+            }
+            count += 1;
+        }
+        info!("replay done after {} records", count);
+    }))
+}
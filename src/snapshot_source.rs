@@ -12,9 +12,11 @@ use anyhow::Context;
 use futures::{stream, StreamExt};
 use log::*;
 use std::str::FromStr;
-use tokio::time;
+use tokio::{sync::watch, task::JoinHandle, time};
 
-use crate::{healthcheck, AnyhowWrap, Config};
+use crate::health_source::{AccountKind, HealthSource};
+use crate::{AnyhowWrap, Config};
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AccountUpdate {
@@ -68,10 +70,11 @@ impl AccountSnapshot {
     }
 }
 
-async fn feed_snapshots(
+/// Retrieve all mango program accounts and the OpenOrders accounts they use
+pub async fn get_snapshot(
     config: &Config,
-    sender: &async_channel::Sender<AccountSnapshot>,
-) -> anyhow::Result<()> {
+    health_source: &dyn HealthSource,
+) -> anyhow::Result<AccountSnapshot> {
     let mango_program_id = Pubkey::from_str(&config.mango_program_id)?;
 
     let rpc_client = http::connect_with_options::<AccountsDataClient>(&config.rpc_http_url, true)
@@ -110,33 +113,15 @@ async fn feed_snapshots(
     }
 
     // Get all the active open orders account keys
-    let oo_account_pubkeys =
-        snapshot
-            .accounts
-            .iter()
-            .filter_map(|update| {
-                if let Ok(mango_account) = healthcheck::load_mango_account::<
-                    mango::state::MangoAccount,
-                >(
-                    mango::state::DataType::MangoAccount, &update.account
-                ) {
-                    if mango_account.mango_group.to_string() == config.mango_group_id {
-                        Some(mango_account)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .flat_map(|mango_account| {
-                mango_account
-                    .in_margin_basket
-                    .iter()
-                    .zip(mango_account.spot_open_orders.iter())
-                    .filter_map(|(in_basket, oo)| in_basket.then(|| *oo))
-            })
-            .collect::<Vec<Pubkey>>();
+    let oo_account_pubkeys = snapshot
+        .accounts
+        .iter()
+        .filter(|update| {
+            health_source.is_interesting_account(&update.pubkey, &update.account)
+                == Some(AccountKind::Health)
+        })
+        .flat_map(|update| health_source.tracked_keys(&update.account))
+        .collect::<Vec<Pubkey>>();
 
     // Retrieve all the open orders accounts
     let results = stream::iter(oo_account_pubkeys)
@@ -166,21 +151,61 @@ async fn feed_snapshots(
         )?;
     }
 
+    Ok(snapshot)
+}
+
+async fn feed_snapshots(
+    config: &Config,
+    health_source: &dyn HealthSource,
+    sender: &async_channel::Sender<AccountSnapshot>,
+) -> anyhow::Result<()> {
+    let snapshot = get_snapshot(config, health_source).await?;
     sender.send(snapshot).await.expect("sending must succeed");
     Ok(())
 }
 
-pub fn start(config: Config, sender: async_channel::Sender<AccountSnapshot>) {
-    let mut interval = time::interval(time::Duration::from_secs(config.snapshot_interval_secs));
+/// Request snapshots regularly, starting once `websocket_connected` is true
+pub fn start(
+    mut config_receiver: watch::Receiver<Config>,
+    mut websocket_connected: watch::Receiver<bool>,
+    health_source: Arc<dyn HealthSource>,
+    sender: async_channel::Sender<AccountSnapshot>,
+) -> JoinHandle<()> {
+    let mut interval_secs = config_receiver.borrow().snapshot_interval_secs;
+    let mut interval = time::interval(time::Duration::from_secs(interval_secs));
 
     tokio::spawn(async move {
+        // Account changes after the slot of the first snapshot must not be missed,
+        // so wait for the websocket subscriptions to be active.
+        while !*websocket_connected.borrow() {
+            if websocket_connected.changed().await.is_err() {
+                return;
+            }
+        }
+
         loop {
-            interval.tick().await;
-            if let Err(err) = feed_snapshots(&config, &sender).await {
-                warn!("snapshot error: {:?}", err);
-            } else {
-                info!("snapshot success");
-            };
+            tokio::select! {
+                _ = interval.tick() => {
+                    let config = config_receiver.borrow().clone();
+                    if let Err(err) = feed_snapshots(&config, health_source.as_ref(), &sender).await {
+                        warn!("snapshot error: {:?}", err);
+                    } else {
+                        info!("snapshot success");
+                    };
+                },
+                changed = config_receiver.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let new_interval_secs = config_receiver.borrow().snapshot_interval_secs;
+                    if new_interval_secs != interval_secs {
+                        info!("snapshot interval changed to {}s", new_interval_secs);
+                        interval_secs = new_interval_secs;
+                        let period = time::Duration::from_secs(interval_secs);
+                        interval = time::interval_at(time::Instant::now() + period, period);
+                    }
+                },
+            }
         }
-    });
+    })
 }
@@ -0,0 +1,144 @@
+use {
+    crate::{metrics, now_unix_timestamp, websocket_sink::ClientCount, Config},
+    hyper::service::{make_service_fn, service_fn},
+    hyper::{Body, Request, Response, Server, StatusCode},
+    log::*,
+    serde_derive::Serialize,
+    std::convert::Infallible,
+    std::sync::Arc,
+    tokio::sync::watch,
+};
+
+#[derive(Serialize)]
+struct Status {
+    /// false if the feed is stale, see `problems`
+    ok: bool,
+    problems: Vec<String>,
+    /// the first snapshot was checked
+    ready: bool,
+    websocket_connected: bool,
+    newest_processed_slot: u64,
+    /// since the newest processed slot arrived, or since startup
+    newest_processed_slot_age_secs: u64,
+    /// since the last snapshot was processed, or since startup
+    last_snapshot_age_secs: u64,
+    tracked_accounts: u64,
+    websocket_sink_clients: u64,
+}
+
+/// What the status is computed from, kept up to date by the rest of the service
+pub struct Inputs {
+    pub websocket_connected: watch::Receiver<bool>,
+    pub ready: watch::Receiver<bool>,
+    pub websocket_clients: ClientCount,
+    pub newest_processed_slot: metrics::MetricU64,
+    /// unix time at which the newest processed slot arrived
+    pub newest_processed_slot_time: metrics::MetricU64,
+    pub last_snapshot_time: metrics::MetricU64,
+    pub tracked_accounts: metrics::MetricU64,
+}
+
+struct State {
+    config: watch::Receiver<Config>,
+    inputs: Inputs,
+    start_time: u64,
+}
+
+impl State {
+    fn status(&self) -> Status {
+        let config = self.config.borrow();
+        let inputs = &self.inputs;
+        let now = now_unix_timestamp();
+        let age = |time: u64| now.saturating_sub(time.max(self.start_time));
+        let slot_age = age(inputs.newest_processed_slot_time.value());
+        let snapshot_age = age(inputs.last_snapshot_time.value());
+
+        let mut problems = vec![];
+        if config.status_max_slot_age_secs > 0 && slot_age > config.status_max_slot_age_secs {
+            problems.push(format!("no new slot for {}s", slot_age));
+        }
+        if config.status_max_snapshot_age_secs > 0
+            && snapshot_age > config.status_max_snapshot_age_secs
+        {
+            problems.push(format!("no snapshot for {}s", snapshot_age));
+        }
+
+        Status {
+            ok: problems.is_empty(),
+            problems,
+            ready: *inputs.ready.borrow(),
+            websocket_connected: *inputs.websocket_connected.borrow(),
+            newest_processed_slot: inputs.newest_processed_slot.value(),
+            newest_processed_slot_age_secs: slot_age,
+            last_snapshot_age_secs: snapshot_age,
+            tracked_accounts: inputs.tracked_accounts.value(),
+            websocket_sink_clients: inputs.websocket_clients.get() as u64,
+        }
+    }
+}
+
+fn handle(state: &State, request: Request<Body>) -> Response<Body> {
+    let status = state.status();
+    let code = if status.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = match request.uri().path() {
+        "/healthz" => {
+            if status.ok {
+                "ok".to_string()
+            } else {
+                status.problems.join("\n")
+            }
+        }
+        "/status" => serde_json::to_string_pretty(&status).unwrap(),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+    Response::builder()
+        .status(code)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Serve /healthz and /status on status_server_bind_address, if set
+///
+/// Both return 503 if no new slot or snapshot arrived in the configured time.
+pub fn start(config: watch::Receiver<Config>, inputs: Inputs) -> anyhow::Result<()> {
+    let bind_address = match &config.borrow().status_server_bind_address {
+        Some(address) => address.clone(),
+        None => return Ok(()),
+    };
+
+    let listener = std::net::TcpListener::bind(&bind_address)?;
+    listener.set_nonblocking(true)?;
+
+    let state = Arc::new(State {
+        config,
+        inputs,
+        start_time: now_unix_timestamp(),
+    });
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(&state, request)) }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)?.serve(make_service);
+    info!("status server listening on: {}", bind_address);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("status server error: {:?}", err);
+        }
+    });
+    Ok(())
+}
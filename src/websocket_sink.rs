@@ -1,5 +1,8 @@
 use {
-    crate::Config,
+    crate::{
+        health::{LiquidationSuggestion, TokenBalance},
+        metrics, Config,
+    },
     anyhow::Context,
     fixed::types::I80F48,
     futures_util::{SinkExt, StreamExt},
@@ -7,25 +10,62 @@ use {
     serde::Serialize,
     //serde_derive::Serialize,
     solana_sdk::pubkey::Pubkey,
-    tokio::net::{TcpListener, TcpStream},
+    std::collections::HashMap,
+    std::fs::File,
+    std::io::BufReader,
+    std::net::SocketAddr,
+    std::sync::atomic::{AtomicBool, Ordering},
+    std::sync::{Arc, Mutex},
+    tokio::io::{AsyncRead, AsyncWrite},
+    tokio::net::TcpListener,
     //std::str::FromStr,
-    tokio::sync::broadcast,
+    tokio::sync::{broadcast, mpsc, watch},
+    tokio_rustls::{rustls, TlsAcceptor},
+    tokio_tungstenite::tungstenite::{
+        handshake::server::{Request, Response},
+        http, Message,
+    },
+    tokio_tungstenite::WebSocketStream,
 };
 
+// Connections that haven't finished the tls and websocket handshakes by then are closed
+const HANDSHAKE_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+
 #[derive(Clone, Debug)]
 pub struct HealthInfo {
     pub account: Pubkey,
+    pub slot: u64, // newest processed slot when health was computed
     pub being_liquidated: bool,
     pub health_fraction: I80F48, // always maint
     pub assets: I80F48,          // always maint
     pub liabilities: I80F48,     // always maint
+    pub liquidation: Option<LiquidationSuggestion>,
+    pub estimated_profit: Option<I80F48>, // native quote
+    pub priority: f64,                    // higher is more urgent
+    pub bankrupt: bool,
+    pub stale_oracle: bool,
+    pub min_slot: u64, // lowest write slot of the account data used
+    pub max_slot: u64, // highest write slot of the account data used
+    pub balances: Vec<TokenBalance>,
 }
 
 #[derive(Clone, Debug)]
 pub enum LiquidationCanditate {
-    Start { info: HealthInfo },
-    Now { info: HealthInfo },
-    Stop { info: HealthInfo },
+    Start {
+        info: HealthInfo,
+    },
+    Now {
+        info: HealthInfo,
+    },
+    /// Sent instead of Now for candidates that are bankrupt
+    Bankruptcy {
+        info: HealthInfo,
+    },
+    Stop {
+        info: HealthInfo,
+    },
+    /// The service is shutting down, clients get notified and disconnected
+    Shutdown,
 }
 
 #[derive(Serialize)]
@@ -36,12 +76,71 @@ struct JsonRpcEnvelope<T: Serialize> {
 }
 
 #[derive(Serialize)]
-struct JsonRpcLiquidatablePayload {
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonRpcLiquidationPayload {
+    TokenAndToken {
+        asset_index: usize,
+        asset_mint: String,
+        liab_index: usize,
+        liab_mint: String,
+        max_liab_transfer: u64,
+        asset_transfer: u64,
+    },
+    PerpMarket {
+        market_index: usize,
+        perp_market: String,
+        base_position: i64,
+        max_base_transfer: i64,
+    },
+}
+
+impl From<&LiquidationSuggestion> for JsonRpcLiquidationPayload {
+    fn from(suggestion: &LiquidationSuggestion) -> Self {
+        match suggestion {
+            LiquidationSuggestion::TokenAndToken {
+                asset_index,
+                asset_mint,
+                liab_index,
+                liab_mint,
+                max_liab_transfer,
+                asset_transfer,
+            } => Self::TokenAndToken {
+                asset_index: *asset_index,
+                asset_mint: asset_mint.to_string(),
+                liab_index: *liab_index,
+                liab_mint: liab_mint.to_string(),
+                max_liab_transfer: max_liab_transfer.saturating_to_num::<u64>(),
+                asset_transfer: asset_transfer.saturating_to_num::<u64>(),
+            },
+            LiquidationSuggestion::PerpMarket {
+                market_index,
+                perp_market,
+                base_position,
+                max_base_transfer,
+            } => Self::PerpMarket {
+                market_index: *market_index,
+                perp_market: perp_market.to_string(),
+                base_position: *base_position,
+                max_base_transfer: *max_base_transfer,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JsonRpcLiquidatablePayload {
     account: String,
     being_liquidated: bool,
     health_fraction: f64,
     assets: u64,
     liabilities: u64,
+    liquidation: Option<JsonRpcLiquidationPayload>,
+    estimated_profit: Option<u64>,
+    priority: f64,
+    bankrupt: bool,
+    stale_oracle: bool,
+    min_slot: u64,
+    max_slot: u64,
 }
 
 impl From<&HealthInfo> for JsonRpcLiquidatablePayload {
@@ -52,39 +151,193 @@ impl From<&HealthInfo> for JsonRpcLiquidatablePayload {
             health_fraction: info.health_fraction.to_num::<f64>(),
             assets: info.assets.to_num::<u64>(),
             liabilities: info.liabilities.to_num::<u64>(),
+            liquidation: info
+                .liquidation
+                .as_ref()
+                .map(JsonRpcLiquidationPayload::from),
+            estimated_profit: info
+                .estimated_profit
+                .map(|profit| profit.saturating_to_num::<u64>()),
+            priority: info.priority,
+            bankrupt: info.bankrupt,
+            stale_oracle: info.stale_oracle,
+            min_slot: info.min_slot,
+            max_slot: info.max_slot,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRpcEmptyPayload {}
+
+/// How messages to a client are serialized, chosen with the `encoding` query parameter
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    /// text messages, the default
+    Json,
+    /// binary messages with the same structure as the json ones
+    MessagePack,
+}
+
+impl Encoding {
+    fn from_query(query: Option<&str>) -> Option<Self> {
+        let value = query
+            .unwrap_or("")
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "encoding")
+            .map(|(_, value)| value);
+        match value {
+            None | Some("json") => Some(Encoding::Json),
+            Some("msgpack") => Some(Encoding::MessagePack),
+            Some(_) => None,
         }
     }
 }
 
-fn jsonrpc_message(method: &str, payload: impl Serialize) -> String {
-    serde_json::to_string(&JsonRpcEnvelope {
+fn jsonrpc_message(encoding: Encoding, method: &str, payload: impl Serialize) -> Message {
+    let envelope = JsonRpcEnvelope {
         jsonrpc: "2.0".into(),
         method: method.into(),
         params: payload,
-    })
-    .unwrap()
+    };
+    match encoding {
+        Encoding::Json => Message::Text(serde_json::to_string(&envelope).unwrap()),
+        Encoding::MessagePack => Message::Binary(rmp_serde::to_vec_named(&envelope).unwrap()),
+    }
+}
+
+/// The messages that are waiting to be sent to a client
+struct ClientQueue {
+    sender: mpsc::Sender<LiquidationCanditate>,
+    /// set when the client fell too far behind and gets disconnected
+    dropped: Arc<AtomicBool>,
 }
 
-async fn accept_connection(
-    stream: TcpStream,
+type ClientQueues = Arc<Mutex<HashMap<SocketAddr, ClientQueue>>>;
+
+/// Forward broadcast messages into the queue of each client
+///
+/// Clients whose queue is full are dropped, so a slow client can't hold up
+/// the others.
+fn start_dispatcher(
     mut rx: broadcast::Receiver<LiquidationCanditate>,
-) -> anyhow::Result<()> {
-    use tokio_tungstenite::tungstenite::Message;
+    clients: ClientQueues,
+    queue_size: usize,
+    metrics: &metrics::Metrics,
+) {
+    let mut metric_dropped_clients = metrics.register_u64("websocket_sink_dropped_clients".into());
+    let mut metric_dropped_messages =
+        metrics.register_u64("websocket_sink_dropped_messages".into());
+    let mut metric_max_client_lag = metrics.register_u64("websocket_sink_max_client_lag".into());
+    tokio::spawn(async move {
+        loop {
+            let message = match rx.recv().await {
+                Ok(message) => message,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    warn!("websocket sink lagged, {} messages lost", count);
+                    let client_count = clients.lock().unwrap().len() as u64;
+                    metric_dropped_messages.add(count * client_count);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
 
-    let addr = stream
-        .peer_addr()
-        .expect("connected streams should have a peer address");
+            let mut max_lag = 0;
+            clients.lock().unwrap().retain(|addr, client| {
+                match client.sender.try_send(message.clone()) {
+                    Ok(()) => {
+                        max_lag = max_lag.max(queue_size - client.sender.capacity());
+                        true
+                    }
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        warn!(
+                            "websocket client at address {} is {} messages behind, disconnecting",
+                            addr, queue_size
+                        );
+                        client.dropped.store(true, Ordering::Relaxed);
+                        metric_dropped_clients.increment();
+                        // the queued messages and this one
+                        metric_dropped_messages.add(queue_size as u64 + 1);
+                        false
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => false,
+                }
+            });
+            metric_max_client_lag.set(max_lag as u64);
+        }
+    });
+}
+
+async fn accept_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    addr: SocketAddr,
+    deadline: tokio::time::Instant,
+    rx: mpsc::Receiver<LiquidationCanditate>,
+    dropped: Arc<AtomicBool>,
+    ready: watch::Receiver<bool>,
+    client_counter: &ClientCounter,
+) -> anyhow::Result<()> {
     info!("new tcp client at address: {}", addr);
 
-    let mut ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("error during the websocket handshake");
-    info!("new websocket client at address: {}", addr);
+    let mut encoding = Encoding::Json;
+    let negotiate_encoding =
+        |request: &Request, response: Response| match Encoding::from_query(request.uri().query()) {
+            Some(requested) => {
+                encoding = requested;
+                Ok(response)
+            }
+            None => Err(http::Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(Some("unknown encoding, use json or msgpack".into()))
+                .unwrap()),
+        };
+    // TODO: Offer permessage-deflate. tokio-tungstenite 0.16 does not implement the
+    // extension and rejects frames with the RSV1 bit it relies on.
+    let ws_stream = tokio::time::timeout_at(
+        deadline,
+        tokio_tungstenite::accept_hdr_async(stream, negotiate_encoding),
+    )
+    .await
+    .context("websocket handshake timed out")?
+    .context("error during the websocket handshake")?;
+    info!(
+        "new websocket client at address: {}, encoding {:?}",
+        addr, encoding
+    );
+
+    // only clients that completed the handshake are counted
+    client_counter.connected();
+    let result = serve_client(ws_stream, encoding, addr, rx, dropped, ready).await;
+    client_counter.disconnected();
+    result
+}
 
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ws_stream: WebSocketStream<S>,
+    encoding: Encoding,
+    addr: SocketAddr,
+    mut rx: mpsc::Receiver<LiquidationCanditate>,
+    dropped: Arc<AtomicBool>,
+    mut ready: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
     let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1000));
 
+    let mut ready_sent = *ready.borrow();
+    if ready_sent {
+        ws_stream
+            .send(jsonrpc_message(encoding, &"ready", JsonRpcEmptyPayload {}))
+            .await?;
+    }
+
     loop {
         tokio::select! {
+            changed = ready.changed(), if !ready_sent => {
+                if changed.is_ok() && *ready.borrow() {
+                    ws_stream.send(jsonrpc_message(encoding, &"ready", JsonRpcEmptyPayload {})).await?;
+                    ready_sent = true;
+                }
+            },
             msg = ws_stream.next() => {
                 match msg {
                     Some(Ok(Message::Ping(data))) => ws_stream.send(Message::Pong(data)).await?,
@@ -93,26 +346,37 @@ async fn accept_connection(
                 }
             },
             data = rx.recv() => {
-                if data.is_err() {
-                    // broadcast stream is lagging or disconnected
-                    // -> drop websocket connection
-                    warn!("liquidation info broadcast receiver had error: {:?}", data);
-                    ws_stream.close(None).await?;
-                    break;
-                }
+                let data = match data {
+                    Some(data) if !dropped.load(Ordering::Relaxed) => data,
+                    _ => {
+                        // the client fell too far behind, or the sink stopped
+                        // -> drop websocket connection
+                        ws_stream.close(None).await?;
+                        break;
+                    }
+                };
 
-                let message = match data.unwrap() {
+                let message = match data {
                     LiquidationCanditate::Start{info} => {
-                        jsonrpc_message(&"candidateStart", JsonRpcLiquidatablePayload::from(&info))
+                        jsonrpc_message(encoding, &"candidateStart", JsonRpcLiquidatablePayload::from(&info))
                     },
                     LiquidationCanditate::Now{info} => {
-                        jsonrpc_message(&"candidate",JsonRpcLiquidatablePayload::from(&info))
+                        jsonrpc_message(encoding, &"candidate",JsonRpcLiquidatablePayload::from(&info))
+                    },
+                    LiquidationCanditate::Bankruptcy{info} => {
+                        jsonrpc_message(encoding, &"candidateBankruptcy", JsonRpcLiquidatablePayload::from(&info))
                     },
                     LiquidationCanditate::Stop{info} => {
-                        jsonrpc_message(&"candidateStop",JsonRpcLiquidatablePayload::from(&info))
+                        jsonrpc_message(encoding, &"candidateStop",JsonRpcLiquidatablePayload::from(&info))
+                    },
+                    LiquidationCanditate::Shutdown => {
+                        info!("notifying websocket client at address {} about shutdown", addr);
+                        ws_stream.send(jsonrpc_message(encoding, &"shutdown", JsonRpcEmptyPayload {})).await?;
+                        ws_stream.close(None).await?;
+                        break;
                     },
                 };
-                ws_stream.send(Message::Text(message)).await?;
+                ws_stream.send(message).await?;
             },
             _ = interval.tick() => {
                 ws_stream.send(Message::Ping(vec![])).await?;
@@ -123,24 +387,180 @@ async fn accept_connection(
     Ok(())
 }
 
-pub async fn start(config: Config) -> anyhow::Result<broadcast::Sender<LiquidationCanditate>> {
+/// The number of connected websocket clients
+#[derive(Clone)]
+pub struct ClientCount {
+    receiver: watch::Receiver<usize>,
+}
+
+impl ClientCount {
+    pub fn get(&self) -> usize {
+        *self.receiver.borrow()
+    }
+
+    /// Wait until all clients have disconnected
+    pub async fn drained(&mut self) {
+        while *self.receiver.borrow() > 0 {
+            if self.receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Keeps the client count metric and the ClientCount handles up to date
+struct ClientCounter {
+    metric: Mutex<metrics::MetricU64>,
+    sender: watch::Sender<usize>,
+}
+
+impl ClientCounter {
+    fn new(metrics: &metrics::Metrics) -> (Self, ClientCount) {
+        let (sender, receiver) = watch::channel(0);
+        let counter = Self {
+            metric: Mutex::new(metrics.register_u64("websocket_sink_clients".into())),
+            sender,
+        };
+        (counter, ClientCount { receiver })
+    }
+
+    fn connected(&self) {
+        let mut metric = self.metric.lock().unwrap();
+        metric.increment();
+        let _ = self.sender.send(metric.value() as usize);
+    }
+
+    fn disconnected(&self) {
+        let mut metric = self.metric.lock().unwrap();
+        metric.decrement();
+        let _ = self.sender.send(metric.value() as usize);
+    }
+}
+
+fn load_tls_acceptor(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).with_context(|| format!("opening {}", cert_path))?,
+    ))
+    .context("reading tls certificates")?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect::<Vec<_>>();
+
+    let mut key_reader =
+        BufReader::new(File::open(key_path).with_context(|| format!("opening {}", key_path))?);
+    let key = rustls_pemfile::read_all(&mut key_reader)
+        .context("reading tls private key")?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", key_path))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(key))
+        .context("invalid tls certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn start(
+    config: Config,
+    metrics: metrics::Metrics,
+    ready: watch::Receiver<bool>,
+) -> anyhow::Result<(broadcast::Sender<LiquidationCanditate>, ClientCount)> {
     // The channel that liquidatable event changes are sent through, to
     // be forwarded to websocket clients
     let (tx, _) = broadcast::channel(1000);
 
+    let tls_acceptor = match (
+        &config.websocket_server_tls_cert_path,
+        &config.websocket_server_tls_key_path,
+    ) {
+        (Some(cert_path), Some(key_path)) => Some(load_tls_acceptor(cert_path, key_path)?),
+        (None, None) => None,
+        _ => anyhow::bail!(
+            "websocket_server_tls_cert_path and websocket_server_tls_key_path must be set together"
+        ),
+    };
+
     let websocket_listener = TcpListener::bind(&config.websocket_server_bind_address)
         .await
         .context("binding websocket server")?;
     info!(
-        "websocket server listening on: {}",
-        &config.websocket_server_bind_address
+        "websocket server listening on: {}{}",
+        &config.websocket_server_bind_address,
+        if tls_acceptor.is_some() {
+            ", with tls"
+        } else {
+            ""
+        }
     );
-    let tx_c = tx.clone();
+    let (client_counter, client_count) = ClientCounter::new(&metrics);
+    let client_counter = Arc::new(client_counter);
+    let queue_size = config.websocket_client_max_lag.max(1);
+    let clients = ClientQueues::default();
+    start_dispatcher(tx.subscribe(), clients.clone(), queue_size, &metrics);
     tokio::spawn(async move {
-        while let Ok((stream, _)) = websocket_listener.accept().await {
-            tokio::spawn(accept_connection(stream, tx_c.subscribe()));
+        while let Ok((stream, addr)) = websocket_listener.accept().await {
+            let (sender, rx) = mpsc::channel(queue_size);
+            let dropped = Arc::new(AtomicBool::new(false));
+            clients.lock().unwrap().insert(
+                addr,
+                ClientQueue {
+                    sender,
+                    dropped: dropped.clone(),
+                },
+            );
+            let clients = clients.clone();
+            let client_counter = client_counter.clone();
+            let ready = ready.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            tokio::spawn(async move {
+                let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+                let result = match tls_acceptor {
+                    Some(acceptor) => {
+                        match tokio::time::timeout_at(deadline, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                accept_connection(
+                                    stream,
+                                    addr,
+                                    deadline,
+                                    rx,
+                                    dropped,
+                                    ready,
+                                    &client_counter,
+                                )
+                                .await
+                            }
+                            Ok(Err(err)) => Err(anyhow::Error::from(err)
+                                .context(format!("tls handshake with {} failed", addr))),
+                            Err(_) => Err(anyhow::anyhow!("tls handshake with {} timed out", addr)),
+                        }
+                    }
+                    None => {
+                        accept_connection(
+                            stream,
+                            addr,
+                            deadline,
+                            rx,
+                            dropped,
+                            ready,
+                            &client_counter,
+                        )
+                        .await
+                    }
+                };
+                if let Err(err) = result {
+                    warn!("websocket client error: {:?}", err);
+                }
+                clients.lock().unwrap().remove(&addr);
+            });
         }
     });
 
-    Ok(tx)
+    Ok((tx, client_count))
 }
@@ -12,6 +12,7 @@ use solana_sdk::{account::AccountSharedData, commitment_config::CommitmentConfig
 
 use log::*;
 use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
 
 use crate::{AnyhowWrap, Config};
 
@@ -44,7 +45,11 @@ pub enum Message {
     Slot(Arc<solana_client::rpc_response::SlotUpdate>),
 }
 
-async fn feed_data(config: &Config, sender: async_channel::Sender<Message>) -> anyhow::Result<()> {
+async fn feed_data(
+    config: &Config,
+    sender: async_channel::Sender<Message>,
+    connected: &watch::Sender<bool>,
+) -> anyhow::Result<()> {
     let mango_program_id = Pubkey::from_str(&config.mango_program_id)?;
     let serum_program_id = Pubkey::from_str(&config.serum_program_id)?;
     let mango_signer_id = Pubkey::from_str(&config.mango_signer_id)?;
@@ -119,6 +124,11 @@ async fn feed_data(config: &Config, sender: async_channel::Sender<Message>) -> a
             },
             message = slot_sub.next() => {
                 if let Some(data) = message {
+                    // the slot subscription is the last one, so once slots flow all are active
+                    if !*connected.borrow() {
+                        info!("solana websocket streams are connected");
+                        let _ = connected.send(true);
+                    }
                     sender.send(Message::Slot(data.map_err_anyhow()?)).await.expect("sending must succeed");
                 } else {
                     warn!("slot update stream closed");
@@ -133,13 +143,21 @@ async fn feed_data(config: &Config, sender: async_channel::Sender<Message>) -> a
     }
 }
 
-pub fn start(config: Config, sender: async_channel::Sender<Message>) {
+/// Stream account and slot updates into `sender`
+///
+/// `connected` is true while the subscriptions are active and slot updates arrive.
+pub fn start(
+    config: Config,
+    sender: async_channel::Sender<Message>,
+    connected: watch::Sender<bool>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         // if the websocket disconnects, we get no data in a while etc, reconnect and try again
         loop {
             info!("connecting to solana websocket streams");
-            let out = feed_data(&config, sender.clone());
+            let out = feed_data(&config, sender.clone(), &connected);
             let _ = out.await;
+            let _ = connected.send(false);
         }
-    });
+    })
 }